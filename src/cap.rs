@@ -0,0 +1,79 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+/// IRCv3 client capabilities offered by the server and enabled on the
+/// current connection.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Capabilities {
+    // Offered capabilities and their values, e.g. `sasl` => `PLAIN,EXTERNAL`.
+    available: BTreeMap<String, String>,
+    enabled: BTreeSet<String>,
+}
+
+impl Capabilities {
+    pub fn new() -> Capabilities {
+        Default::default()
+    }
+
+    pub fn is_enabled(&self, name: &str) -> bool {
+        self.enabled.contains(name)
+    }
+
+    pub fn is_available(&self, name: &str) -> bool {
+        self.available.contains_key(name)
+    }
+
+    /// The value the server advertised with a capability, if any.
+    pub fn value(&self, name: &str) -> Option<&str> {
+        match self.available.get(name) {
+            Some(value) if !value.is_empty() => Some(&value[..]),
+            _ => None,
+        }
+    }
+
+    pub fn enabled(&self) -> Vec<&str> {
+        self.enabled.iter().map(|name| &name[..]).collect()
+    }
+
+    pub fn available(&self) -> Vec<&str> {
+        self.available.keys().map(|name| &name[..]).collect()
+    }
+
+    /// Applies a `CAP` subcommand and its capability list, returning
+    /// whether anything changed.
+    pub fn apply(&mut self, subcommand: &str, caps: &str) -> bool {
+        let mut changed = false;
+        for cap in caps.split(' ').filter(|cap| !cap.is_empty()) {
+            changed |= match subcommand {
+                "LS" | "NEW" => {
+                    let (name, value) = split_cap(cap);
+                    self.available.insert(name.to_string(), value.to_string()) != Some(value.to_string())
+                },
+                "DEL" => {
+                    let (name, _) = split_cap(cap);
+                    let removed = self.available.remove(name).is_some();
+                    self.enabled.remove(name) || removed
+                },
+                "ACK" | "LIST" => {
+                    // Modifiers from 3.1: `-` disables, `~` and `=` are
+                    // informational.
+                    let cap = cap.trim_left_matches(|ch| ch == '~' || ch == '=');
+                    if cap.starts_with('-') {
+                        self.enabled.remove(&cap[1..])
+                    } else {
+                        self.enabled.insert(split_cap(cap).0.to_string())
+                    }
+                },
+                _ => false,
+            };
+        }
+        changed
+    }
+}
+
+fn split_cap(cap: &str) -> (&str, &str) {
+    match cap.find('=') {
+        Some(idx) => (&cap[..idx], &cap[idx + 1..]),
+        None => (cap, ""),
+    }
+}
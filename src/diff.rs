@@ -0,0 +1,102 @@
+use {ChannelId, State, UserId};
+
+/// Differences between two snapshots of the same `State`, as reported
+/// by `FrozenState::diff`.  Every list is sorted.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct StateDiff {
+    pub users_added: Vec<UserId>,
+    pub users_removed: Vec<UserId>,
+    pub channels_added: Vec<ChannelId>,
+    pub channels_removed: Vec<ChannelId>,
+    pub members_added: Vec<(ChannelId, UserId)>,
+    pub members_removed: Vec<(ChannelId, UserId)>,
+    pub member_modes_changed: Vec<(ChannelId, UserId)>,
+    /// `(user, old nick, new nick)`
+    pub nick_changes: Vec<(UserId, String, String)>,
+    pub topic_changes: Vec<ChannelId>,
+}
+
+impl StateDiff {
+    pub fn is_empty(&self) -> bool {
+        self.users_added.is_empty()
+            && self.users_removed.is_empty()
+            && self.channels_added.is_empty()
+            && self.channels_removed.is_empty()
+            && self.members_added.is_empty()
+            && self.members_removed.is_empty()
+            && self.member_modes_changed.is_empty()
+            && self.nick_changes.is_empty()
+            && self.topic_changes.is_empty()
+    }
+}
+
+pub fn diff(older: &State, newer: &State) -> StateDiff {
+    let mut diff = StateDiff::default();
+
+    for (&id, user) in newer.users.iter() {
+        match older.users.get(&id) {
+            Some(old_user) => {
+                if old_user.get_nick() != user.get_nick() {
+                    diff.nick_changes.push((
+                        id, old_user.get_nick().to_string(), user.get_nick().to_string()));
+                }
+            },
+            None => diff.users_added.push(id),
+        }
+    }
+    for &id in older.users.keys() {
+        if !newer.users.contains_key(&id) {
+            diff.users_removed.push(id);
+        }
+    }
+
+    for (&chan_id, channel) in newer.channels.iter() {
+        let old_channel = match older.channels.get(&chan_id) {
+            Some(old_channel) => old_channel,
+            None => {
+                diff.channels_added.push(chan_id);
+                for &user_id in channel.users.keys() {
+                    diff.members_added.push((chan_id, user_id));
+                }
+                continue;
+            }
+        };
+        if old_channel.topic != channel.topic {
+            diff.topic_changes.push(chan_id);
+        }
+        for (&user_id, member) in channel.users.iter() {
+            match old_channel.users.get(&user_id) {
+                Some(old_member) => {
+                    if old_member != member {
+                        diff.member_modes_changed.push((chan_id, user_id));
+                    }
+                },
+                None => diff.members_added.push((chan_id, user_id)),
+            }
+        }
+        for &user_id in old_channel.users.keys() {
+            if !channel.users.contains_key(&user_id) {
+                diff.members_removed.push((chan_id, user_id));
+            }
+        }
+    }
+    for (&chan_id, old_channel) in older.channels.iter() {
+        if !newer.channels.contains_key(&chan_id) {
+            diff.channels_removed.push(chan_id);
+            for &user_id in old_channel.users.keys() {
+                diff.members_removed.push((chan_id, user_id));
+            }
+        }
+    }
+
+    diff.users_added.sort();
+    diff.users_removed.sort();
+    diff.channels_added.sort();
+    diff.channels_removed.sort();
+    diff.members_added.sort();
+    diff.members_removed.sort();
+    diff.member_modes_changed.sort();
+    diff.nick_changes.sort();
+    diff.topic_changes.sort();
+    diff
+}
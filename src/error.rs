@@ -0,0 +1,63 @@
+use std::error::Error;
+use std::fmt;
+
+use event::StateChange;
+
+/// Why a message couldn't be applied to a `State`.
+///
+/// Messages are checked against the state before being applied, and the
+/// state stays internally consistent after an error, so callers may log
+/// it and continue feeding events.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StateError {
+    /// A message referenced a channel we aren't tracking.
+    UnknownChannel(String),
+    /// A message referenced a nick we aren't tracking.
+    UnknownUser(String),
+    /// A channel or nick wasn't valid UTF-8.
+    NonUtf8Name(Vec<u8>),
+    /// Internal bookkeeping disagrees with itself.
+    Inconsistent(String),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::UnknownChannel(ref name) => write!(f, "unknown channel {:?}", name),
+            StateError::UnknownUser(ref nick) => write!(f, "unknown user {:?}", nick),
+            StateError::NonUtf8Name(ref name) => write!(f, "non-UTF-8 name {:?}", name),
+            StateError::Inconsistent(ref msg) => write!(f, "inconsistent state: {}", msg),
+        }
+    }
+}
+
+impl Error for StateError {
+    fn description(&self) -> &str {
+        match *self {
+            StateError::UnknownChannel(_) => "unknown channel",
+            StateError::UnknownUser(_) => "unknown user",
+            StateError::NonUtf8Name(_) => "non-UTF-8 name",
+            StateError::Inconsistent(_) => "inconsistent state",
+        }
+    }
+}
+
+/// A `StateError` raised while applying an event, with the changes the
+/// event had already made.  Those changes stay applied.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EventError {
+    pub error: StateError,
+    pub changes: Vec<StateChange>,
+}
+
+impl fmt::Display for EventError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl Error for EventError {
+    fn description(&self) -> &str {
+        self.error.description()
+    }
+}
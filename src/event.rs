@@ -0,0 +1,88 @@
+use lifecycle::Lifecycle;
+use mode::ModeChange;
+use {ChannelId, UserId};
+
+/// Why a user stopped being a member of a channel.
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum RemovalReason {
+    Part,
+    Kick,
+    Quit,
+}
+
+/// Why the server refused a nick we asked for.
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum NickRejection {
+    /// ERR_NICKNAMEINUSE (433)
+    InUse,
+    /// ERR_NICKCOLLISION (436)
+    Collision,
+    /// ERR_ERRONEUSNICKNAME (432)
+    Erroneous,
+}
+
+/// A single mutation applied to a `State` while handling an event.
+///
+/// Identifiers refer to the state as it is after the event.  Removed
+/// entities can no longer be resolved, so removals carry their names.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StateChange {
+    LifecycleChanged(Lifecycle),
+    /// Capabilities were offered, withdrawn, enabled or disabled.
+    CapabilitiesChanged,
+    UserAdded(UserId),
+    UserRemoved {
+        user: UserId,
+        nick: String,
+    },
+    NickChanged {
+        user: UserId,
+        old: String,
+        new: String,
+    },
+    /// A nick change or registration attempt was refused; our nick is
+    /// unchanged.
+    NickRejected {
+        nick: String,
+        reason: NickRejection,
+    },
+    /// The user logged in to or out of a services account.
+    AccountChanged(UserId),
+    /// The user's user@host changed, e.g. through CHGHOST or a WHO reply.
+    HostChanged(UserId),
+    RealnameChanged(UserId),
+    /// The user went away, came back or changed their away message.
+    AwayChanged(UserId),
+    ChannelJoined(ChannelId),
+    ChannelParted {
+        channel: ChannelId,
+        name: String,
+    },
+    MemberAdded {
+        channel: ChannelId,
+        user: UserId,
+    },
+    MemberRemoved {
+        channel: ChannelId,
+        user: UserId,
+        channel_name: String,
+        nick: String,
+        reason: RemovalReason,
+    },
+    MemberModesChanged {
+        channel: ChannelId,
+        user: UserId,
+    },
+    TopicChanged(ChannelId),
+    /// The channel's creation time or URL changed.
+    ChannelMetadataChanged(ChannelId),
+    ModeChanged {
+        channel: ChannelId,
+        change: ModeChange,
+    },
+    /// A list mode such as `b` was refreshed from the server's list.
+    ModeListChanged {
+        channel: ChannelId,
+        mode: char,
+    },
+}
@@ -0,0 +1,241 @@
+use isupport::ISupport;
+use mask;
+use {State, User};
+
+/// A list mode entry, either a plain hostmask or an extended ban such as
+/// `$a:account` (charybdis), `~q:nick!*@*` (UnrealIRCd) or `m:mask`
+/// (InspIRCd).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExtBan<'a> {
+    Mask(&'a str),
+    Ext {
+        /// The server's extban prefix, such as `$`; some types mean
+        /// different things on different servers.
+        prefix: Option<char>,
+        /// `$~a`: matches users the unnegated ban wouldn't.
+        negated: bool,
+        kind: char,
+        arg: Option<&'a str>,
+    },
+}
+
+impl<'a> ExtBan<'a> {
+    /// Parses a list entry against the server's EXTBAN token.  Entries
+    /// that don't use an advertised extban type are plain masks.
+    pub fn parse(entry: &'a str, isupport: &ISupport) -> ExtBan<'a> {
+        let (prefix, types) = match isupport.extban() {
+            Some(extban) => extban,
+            None => return ExtBan::Mask(entry),
+        };
+        let rest = match prefix {
+            Some(prefix) if entry.starts_with(prefix) => &entry[prefix.len_utf8()..],
+            Some(_) => return ExtBan::Mask(entry),
+            // Without a prefix, extbans are told apart by `<type>:`.
+            None => match entry.find(':') {
+                Some(idx) if entry[..idx].chars().count() == 1 => entry,
+                _ => return ExtBan::Mask(entry),
+            },
+        };
+        let (negated, rest) = if prefix.is_some() && rest.starts_with('~') {
+            (true, &rest[1..])
+        } else {
+            (false, rest)
+        };
+        let kind = match rest.chars().next() {
+            Some(kind) if types.contains(&kind) => kind,
+            _ => return ExtBan::Mask(entry),
+        };
+        let rest = &rest[kind.len_utf8()..];
+        let arg = if rest.starts_with(':') { Some(&rest[1..]) } else { None };
+        if !rest.is_empty() && arg.is_none() {
+            return ExtBan::Mask(entry);
+        }
+        ExtBan::Ext {
+            prefix: prefix,
+            negated: negated,
+            kind: kind,
+            arg: arg,
+        }
+    }
+
+    /// Whether the entry silences rather than bans (`~q:` and `m:`); the
+    /// argument is then the mask of who is silenced.
+    pub fn is_quiet(&self) -> bool {
+        match *self {
+            ExtBan::Ext { kind: 'q', .. } | ExtBan::Ext { kind: 'm', .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the entry only stops matching users from joining
+    /// (UnrealIRCd's `~j:`), leaving members free to speak.
+    pub fn is_join_only(&self) -> bool {
+        match *self {
+            ExtBan::Ext { prefix: Some('~'), kind: 'j', .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the entry applies to `user`.  Types we can't evaluate,
+    /// such as TLS or oper status, never match.
+    pub fn matches(&self, user: &User, state: &State) -> bool {
+        matches_at(self, user, state, 0)
+    }
+}
+
+/// Whether `user` is in the channel named `channel`.  A status prefix
+/// such as `@#chan` limits it to members with that status.
+fn is_member(user: &User, state: &State, channel: &str) -> bool {
+    let (status, name) = state.isupport.split_status_prefixes(channel);
+    match state.identify_channel(name).and_then(|chid| state.membership(chid, user.id)) {
+        Some(member) => status.iter().all(|&mode| member.has_mode(mode)),
+        None => false,
+    }
+}
+
+/// `ExtBan::matches`, where `depth` counts the charybdis `$j` extbans
+/// followed to get here.  Like charybdis, we don't follow a second one.
+pub fn matches_at(ban: &ExtBan, user: &User, state: &State, depth: u32) -> bool {
+    let casemapping = state.isupport.casemapping();
+    let (prefix, negated, kind, arg) = match *ban {
+        ExtBan::Mask(mask) => {
+            return mask::matches(&mask::normalize(mask), &user.mask_subject(), casemapping);
+        },
+        ExtBan::Ext { prefix, negated, kind, arg } => (prefix, negated, kind, arg),
+    };
+    let matched = match (kind, arg) {
+        // Actions wrap another entry, which may itself be an extban.
+        ('q', Some(inner)) | ('m', Some(inner)) => {
+            matches_at(&ExtBan::parse(inner, &state.isupport), user, state, depth)
+        },
+        ('j', Some(inner)) if prefix == Some('~') => {
+            matches_at(&ExtBan::parse(inner, &state.isupport), user, state, depth)
+        },
+        ('a', None) | ('R', None) => user.account().is_some(),
+        ('a', Some(account)) | ('R', Some(account)) => match user.account() {
+            Some(user_account) => mask::matches(account, user_account, casemapping),
+            None => false,
+        },
+        // An unknown realname can't be said to match or not.
+        ('r', Some(realname)) => match user.realname() {
+            Some(user_realname) => mask::matches(realname, user_realname, casemapping),
+            None => return false,
+        },
+        // Channel membership is `$c` and `~c`, but InspIRCd's `j:`.
+        ('c', Some(channel)) if prefix.is_some() => is_member(user, state, channel),
+        ('j', Some(channel)) if prefix.is_none() => is_member(user, state, channel),
+        // charybdis: users banned in another channel we're in.
+        ('j', Some(channel)) if prefix == Some('$') => {
+            let chid = match state.identify_channel(channel) {
+                Some(chid) if depth == 0 => chid,
+                _ => return false,
+            };
+            !state.bans_matching_at(chid, user.id, depth + 1).is_empty()
+        },
+        ('x', Some(full)) => {
+            let subject = format!("{}#{}", user.mask_subject(), user.realname().unwrap_or(""));
+            mask::matches(full, &subject, casemapping)
+        },
+        _ => return false,
+    };
+    matched != negated
+}
+
+#[cfg(test)]
+mod tests {
+    use isupport::ISupport;
+    use tests::{feed, joined};
+    use State;
+    use super::ExtBan;
+
+    fn isupport(extban: &str) -> ISupport {
+        let mut isupport = ISupport::new();
+        isupport.apply_token(extban);
+        isupport
+    }
+
+    fn state(extban: &str, lines: &[&str]) -> State {
+        let mut state = joined("#chan", &["me", "@bob!b@bad.example"]);
+        feed(&mut state, ":server CAP * ACK :extended-join").unwrap();
+        feed(&mut state, &format!(":server 005 me {} :are supported", extban)).unwrap();
+        for line in lines.iter() {
+            feed(&mut state, line).unwrap();
+        }
+        state
+    }
+
+    fn matching(state: &State, entry: &str) -> Vec<String> {
+        let mut nicks: Vec<String> = state.users_matching_mask(entry).into_iter()
+            .map(|uid| state.resolve_user(uid).unwrap().get_nick().to_string())
+            .collect();
+        nicks.sort();
+        nicks
+    }
+
+    #[test]
+    fn test_parse_prefixed() {
+        let isupport = isupport("EXTBAN=$,ajrxz");
+        assert_eq!(
+            ExtBan::parse("$a:acct", &isupport),
+            ExtBan::Ext { prefix: Some('$'), negated: false, kind: 'a', arg: Some("acct") });
+        assert_eq!(
+            ExtBan::parse("$~a", &isupport),
+            ExtBan::Ext { prefix: Some('$'), negated: true, kind: 'a', arg: None });
+        assert_eq!(
+            ExtBan::parse("$x:nick!*@*#real name", &isupport),
+            ExtBan::Ext { prefix: Some('$'), negated: false, kind: 'x', arg: Some("nick!*@*#real name") });
+    }
+
+    #[test]
+    fn test_parse_unprefixed() {
+        let isupport = isupport("EXTBAN=,jmR");
+        assert_eq!(
+            ExtBan::parse("m:*!*@host", &isupport),
+            ExtBan::Ext { prefix: None, negated: false, kind: 'm', arg: Some("*!*@host") });
+        assert_eq!(
+            ExtBan::parse("R:acct", &isupport),
+            ExtBan::Ext { prefix: None, negated: false, kind: 'R', arg: Some("acct") });
+    }
+
+    #[test]
+    fn test_parse_plain_masks() {
+        let isupport_prefixed = isupport("EXTBAN=$,ajrxz");
+        assert_eq!(ExtBan::parse("*!*@host", &isupport_prefixed), ExtBan::Mask("*!*@host"));
+        // `q` isn't advertised, and `$ab` isn't `<type>:<arg>`.
+        assert_eq!(ExtBan::parse("$q:*!*@*", &isupport_prefixed), ExtBan::Mask("$q:*!*@*"));
+        assert_eq!(ExtBan::parse("$ab", &isupport_prefixed), ExtBan::Mask("$ab"));
+        let isupport_unprefixed = isupport("EXTBAN=,jmR");
+        assert_eq!(ExtBan::parse("nick!u:x@host", &isupport_unprefixed), ExtBan::Mask("nick!u:x@host"));
+        assert_eq!(ExtBan::parse("$a:acct", &ISupport::new()), ExtBan::Mask("$a:acct"));
+    }
+
+    #[test]
+    fn test_match_accounts() {
+        let state = state("EXTBAN=$,ajrxz", &[":al!a@good.example JOIN #chan alacct :Al Person"]);
+        assert_eq!(matching(&state, "$a:ALACCT"), vec!["al"]);
+        assert_eq!(matching(&state, "$a"), vec!["al"]);
+        assert_eq!(matching(&state, "$~a"), vec!["bob", "me"]);
+        assert_eq!(matching(&state, "$r:Al*"), vec!["al"]);
+        assert_eq!(matching(&state, "$x:al!*@*#Al Person"), vec!["al"]);
+        assert_eq!(matching(&state, "*!*@bad.example"), vec!["bob"]);
+        // Types we can't evaluate match nobody, negated or not.
+        assert!(matching(&state, "$z").is_empty());
+        assert!(matching(&state, "$~z").is_empty());
+    }
+
+    #[test]
+    fn test_match_channels() {
+        let charybdis = state("EXTBAN=$,cj", &[":op!o@host MODE #chan +b bob!*@*"]);
+        assert_eq!(matching(&charybdis, "$c:#chan"), vec!["bob", "me"]);
+        assert_eq!(matching(&charybdis, "$c:@#chan"), vec!["bob"]);
+        assert_eq!(matching(&charybdis, "$j:#chan"), vec!["bob"]);
+
+        let inspircd = state("EXTBAN=,jm", &[]);
+        assert_eq!(matching(&inspircd, "j:#chan"), vec!["bob", "me"]);
+
+        let unreal = state("EXTBAN=~,cjq", &[]);
+        assert_eq!(matching(&unreal, "~c:#chan"), vec!["bob", "me"]);
+        assert_eq!(matching(&unreal, "~j:bob!*@*"), vec!["bob"]);
+        assert!(ExtBan::parse("~j:bob!*@*", unreal.isupport()).is_join_only());
+    }
+}
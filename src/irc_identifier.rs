@@ -1,9 +1,62 @@
-use irc::irccase::IrcAsciiExt;
+/// The case-folding rules advertised by the CASEMAPPING ISUPPORT token.
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum CaseMapping {
+    /// Only `A-Z` fold to `a-z`.
+    Ascii,
+    /// `A-Z[]\~` fold to `a-z{}|^`; the default for IRC.
+    Rfc1459,
+    /// `A-Z[]\` fold to `a-z{}|`.
+    StrictRfc1459,
+    /// Unicode case folding, as used by PRECIS-aware servers.
+    ///
+    /// Normalization is not applied; identifiers are folded with
+    /// `char::to_lowercase` only.
+    Rfc7613,
+}
 
-fn channel_deprefix(target: &str) -> &str {
-    match target.find('#') {
-        Some(idx) => &target[idx..],
-        None => target
+impl Default for CaseMapping {
+    fn default() -> CaseMapping {
+        CaseMapping::Rfc1459
+    }
+}
+
+impl CaseMapping {
+    pub fn from_name(name: &str) -> Option<CaseMapping> {
+        match name {
+            "ascii" => Some(CaseMapping::Ascii),
+            "rfc1459" => Some(CaseMapping::Rfc1459),
+            "strict-rfc1459" => Some(CaseMapping::StrictRfc1459),
+            "rfc7613" => Some(CaseMapping::Rfc7613),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            CaseMapping::Ascii => "ascii",
+            CaseMapping::Rfc1459 => "rfc1459",
+            CaseMapping::StrictRfc1459 => "strict-rfc1459",
+            CaseMapping::Rfc7613 => "rfc7613",
+        }
+    }
+
+    pub fn to_lower(&self, val: &str) -> String {
+        let mut out = String::with_capacity(val.len());
+        for ch in val.chars() {
+            match (*self, ch) {
+                (CaseMapping::Rfc7613, ch) => out.extend(ch.to_lowercase()),
+                (_, ch) if 'A' <= ch && ch <= 'Z' => out.push(((ch as u8) + (b'a' - b'A')) as char),
+                (CaseMapping::Rfc1459, '[') => out.push('{'),
+                (CaseMapping::Rfc1459, ']') => out.push('}'),
+                (CaseMapping::Rfc1459, '\\') => out.push('|'),
+                (CaseMapping::Rfc1459, '~') => out.push('^'),
+                (CaseMapping::StrictRfc1459, '[') => out.push('{'),
+                (CaseMapping::StrictRfc1459, ']') => out.push('}'),
+                (CaseMapping::StrictRfc1459, '\\') => out.push('|'),
+                (_, ch) => out.push(ch),
+            }
+        }
+        out
     }
 }
 
@@ -11,13 +64,47 @@ fn channel_deprefix(target: &str) -> &str {
 pub struct IrcIdentifier(String);
 
 impl IrcIdentifier {
-    pub fn from_str(mut val: &str) -> IrcIdentifier {
-        val = channel_deprefix(val);
-        IrcIdentifier(val.to_irc_lower())
+    pub fn new(val: &str, casemapping: CaseMapping) -> IrcIdentifier {
+        IrcIdentifier(casemapping.to_lower(val))
     }
+}
 
-    pub fn as_slice(&self) -> &str {
-        let IrcIdentifier(ref string) = *self;
-        &string[..]
+#[cfg(test)]
+mod tests {
+    use super::{CaseMapping, IrcIdentifier};
+
+    #[test]
+    fn test_rfc1459_folding() {
+        let mapping = CaseMapping::Rfc1459;
+        assert_eq!(
+            IrcIdentifier::new("Nick[]\\~", mapping),
+            IrcIdentifier::new("nick{}|^", mapping));
+    }
+
+    #[test]
+    fn test_strict_rfc1459_folding() {
+        let mapping = CaseMapping::StrictRfc1459;
+        assert_eq!(
+            IrcIdentifier::new("Nick[]\\", mapping),
+            IrcIdentifier::new("nick{}|", mapping));
+        assert!(IrcIdentifier::new("~", mapping) != IrcIdentifier::new("^", mapping));
+    }
+
+    #[test]
+    fn test_ascii_folding() {
+        let mapping = CaseMapping::Ascii;
+        assert_eq!(
+            IrcIdentifier::new("NICK", mapping),
+            IrcIdentifier::new("nick", mapping));
+        assert!(IrcIdentifier::new("[]\\~", mapping) != IrcIdentifier::new("{}|^", mapping));
+    }
+
+    #[test]
+    fn test_rfc7613_folding() {
+        let mapping = CaseMapping::Rfc7613;
+        assert_eq!(
+            IrcIdentifier::new("ÉLAN", mapping),
+            IrcIdentifier::new("élan", mapping));
+        assert!(IrcIdentifier::new("[", mapping) != IrcIdentifier::new("{", mapping));
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,272 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use irc_identifier::CaseMapping;
+use mode::ModeKind;
+
+/// Server parameters advertised through RPL_ISUPPORT (005).
+///
+/// Raw tokens are retained as sent; the typed accessors fall back to
+/// RFC 1459 behaviour for anything the server hasn't advertised.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ISupport {
+    tokens: BTreeMap<String, String>,
+
+    casemapping: CaseMapping,
+    prefixes: Vec<(char, char)>,
+    chantypes: Vec<char>,
+    chanmodes: [String; 4],
+    nicklen: Option<u32>,
+    statusmsg: Vec<char>,
+    modes: Option<u32>,
+    targmax: BTreeMap<String, Option<u32>>,
+    network: Option<String>,
+    extban: Option<(Option<char>, Vec<char>)>,
+}
+
+impl Default for ISupport {
+    fn default() -> ISupport {
+        ISupport::new()
+    }
+}
+
+impl ISupport {
+    pub fn new() -> ISupport {
+        let mut isupport = ISupport {
+            tokens: BTreeMap::new(),
+            casemapping: CaseMapping::Rfc1459,
+            prefixes: Vec::new(),
+            chantypes: Vec::new(),
+            chanmodes: [String::new(), String::new(), String::new(), String::new()],
+            nicklen: None,
+            statusmsg: Vec::new(),
+            modes: None,
+            targmax: BTreeMap::new(),
+            network: None,
+            extban: None,
+        };
+        isupport.refresh();
+        isupport
+    }
+
+    /// Applies a single token such as `PREFIX=(ov)@+`, `EXCEPTS` or
+    /// `-KNOCK` as found in a 005 line.
+    pub fn apply_token(&mut self, token: &str) {
+        if token.starts_with('-') {
+            self.tokens.remove(&token[1..]);
+        } else {
+            let (name, value) = match token.find('=') {
+                Some(idx) => (&token[..idx], unescape_value(&token[idx + 1..])),
+                None => (token, String::new()),
+            };
+            self.tokens.insert(name.to_string(), value);
+        }
+        self.refresh();
+    }
+
+    fn refresh(&mut self) {
+        let casemapping = self.get("CASEMAPPING").unwrap_or("rfc1459").to_ascii_lowercase();
+        self.casemapping = match CaseMapping::from_name(&casemapping) {
+            Some(casemapping) => casemapping,
+            None => {
+                warn!("Unknown CASEMAPPING {:?}, assuming rfc1459", casemapping);
+                CaseMapping::Rfc1459
+            }
+        };
+
+        self.prefixes = parse_prefix(self.get("PREFIX").unwrap_or("(ov)@+"));
+
+        self.chantypes = self.get("CHANTYPES").unwrap_or("#&").chars().collect();
+
+        let mut chanmodes = [String::new(), String::new(), String::new(), String::new()];
+        let chanmodes_raw = self.get("CHANMODES").unwrap_or("beI,k,l,imnpst");
+        for (slot, group) in chanmodes.iter_mut().zip(chanmodes_raw.split(',')) {
+            slot.push_str(group);
+        }
+        self.chanmodes = chanmodes;
+
+        self.nicklen = match self.get("NICKLEN") {
+            Some(val) => val.parse().ok(),
+            None => Some(9),
+        };
+
+        self.statusmsg = self.get("STATUSMSG").unwrap_or("").chars().collect();
+
+        self.modes = match self.get("MODES") {
+            Some(val) => val.parse().ok(),
+            None => Some(3),
+        };
+
+        let mut targmax = BTreeMap::new();
+        for entry in self.get("TARGMAX").unwrap_or("").split(',') {
+            if let Some(idx) = entry.find(':') {
+                let command = entry[..idx].to_ascii_uppercase();
+                targmax.insert(command, entry[idx + 1..].parse().ok());
+            }
+        }
+        self.targmax = targmax;
+
+        self.network = self.get("NETWORK").map(|v| v.to_string());
+
+        // EXTBAN=<prefix>,<types>, where the prefix may be empty.
+        self.extban = self.get("EXTBAN").and_then(|val| {
+            val.find(',').map(|idx| (val[..idx].chars().next(), val[idx + 1..].chars().collect()))
+        });
+    }
+
+    /// The raw value of a token; value-less tokens yield `Some("")`.
+    pub fn get(&self, token: &str) -> Option<&str> {
+        self.tokens.get(token).map(|v| &v[..])
+    }
+
+    pub fn casemapping(&self) -> CaseMapping {
+        self.casemapping
+    }
+
+    /// Status modes and their symbols, highest rank first.
+    pub fn prefixes(&self) -> &[(char, char)] {
+        &self.prefixes
+    }
+
+    pub fn prefix_mode(&self, symbol: char) -> Option<char> {
+        self.prefixes.iter()
+            .find(|&&(_, s)| s == symbol)
+            .map(|&(m, _)| m)
+    }
+
+    pub fn prefix_symbol(&self, mode: char) -> Option<char> {
+        self.prefixes.iter()
+            .find(|&&(m, _)| m == mode)
+            .map(|&(_, s)| s)
+    }
+
+    /// Strips leading status symbols from a NAMES entry such as `@+nick`,
+    /// returning the status modes and the remainder.
+    pub fn split_status_prefixes<'a>(&self, name: &'a str) -> (BTreeSet<char>, &'a str) {
+        let mut modes = BTreeSet::new();
+        for (idx, ch) in name.char_indices() {
+            match self.prefix_mode(ch) {
+                Some(mode) => { modes.insert(mode); },
+                None => return (modes, &name[idx..]),
+            }
+        }
+        (modes, "")
+    }
+
+    pub fn chantypes(&self) -> &[char] {
+        &self.chantypes
+    }
+
+    pub fn is_channel_name(&self, name: &str) -> bool {
+        match name.chars().next() {
+            Some(ch) => self.chantypes.contains(&ch),
+            None => false,
+        }
+    }
+
+    pub fn mode_kind(&self, mode: char) -> ModeKind {
+        if self.prefix_symbol(mode).is_some() {
+            return ModeKind::Prefix;
+        }
+        let kinds = [
+            ModeKind::List,
+            ModeKind::AlwaysArg,
+            ModeKind::SetArg,
+            ModeKind::NoArg,
+        ];
+        for (group, kind) in self.chanmodes.iter().zip(kinds.iter()) {
+            if group.contains(mode) {
+                return *kind;
+            }
+        }
+        ModeKind::NoArg
+    }
+
+    pub fn nicklen(&self) -> Option<u32> {
+        self.nicklen
+    }
+
+    pub fn statusmsg(&self) -> &[char] {
+        &self.statusmsg
+    }
+
+    /// Strips STATUSMSG symbols from a message target such as `@#chan`.
+    pub fn strip_statusmsg<'a>(&self, target: &'a str) -> &'a str {
+        match target.find(|ch: char| !self.statusmsg.contains(&ch)) {
+            Some(idx) => &target[idx..],
+            None => target,
+        }
+    }
+
+    /// The number of parameterised mode changes allowed per MODE command.
+    pub fn modes(&self) -> Option<u32> {
+        self.modes
+    }
+
+    /// The maximum number of targets for a command, or `None` when
+    /// unlimited or unspecified.
+    pub fn targmax(&self, command: &str) -> Option<u32> {
+        match self.targmax.get(&command.to_ascii_uppercase()) {
+            Some(&limit) => limit,
+            None => None,
+        }
+    }
+
+    pub fn network(&self) -> Option<&str> {
+        self.network.as_ref().map(|v| &v[..])
+    }
+
+    /// The extban prefix, if any, and the supported extban types; `None`
+    /// if the server doesn't advertise EXTBAN.
+    pub fn extban(&self) -> Option<(Option<char>, &[char])> {
+        self.extban.as_ref().map(|&(prefix, ref types)| (prefix, &types[..]))
+    }
+}
+
+// Only the raw tokens are stored; everything else is derived from them.
+#[cfg(feature = "serde")]
+impl ::serde::Serialize for ISupport {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: ::serde::Serializer {
+        ::serde::Serialize::serialize(&self.tokens, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> ::serde::Deserialize<'de> for ISupport {
+    fn deserialize<D>(deserializer: D) -> Result<ISupport, D::Error> where D: ::serde::Deserializer<'de> {
+        let mut isupport = ISupport::new();
+        isupport.tokens = try!(::serde::Deserialize::deserialize(deserializer));
+        isupport.refresh();
+        Ok(isupport)
+    }
+}
+
+fn parse_prefix(value: &str) -> Vec<(char, char)> {
+    if !value.starts_with('(') {
+        return Vec::new();
+    }
+    match value.find(')') {
+        Some(idx) => value[1..idx].chars().zip(value[idx + 1..].chars()).collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Decodes the `\xHH` escapes permitted in token values.
+fn unescape_value(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        if bytes[idx] == b'\\' && idx + 4 <= bytes.len() && bytes[idx + 1] == b'x' {
+            let hex = ::std::str::from_utf8(&bytes[idx + 2..idx + 4]).ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            if let Some(byte) = hex {
+                out.push(byte);
+                idx += 4;
+                continue;
+            }
+        }
+        out.push(bytes[idx]);
+        idx += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
@@ -4,18 +4,40 @@
 
 #[macro_use] extern crate log;
 extern crate irc;
-
+#[cfg(feature = "serde")]
+#[macro_use] extern crate serde;
+
+mod cap;
+mod diff;
+mod error;
+mod event;
+mod extban;
 mod irc_identifier;
+mod isupport;
+mod lifecycle;
+mod mask;
+mod mode;
+mod names;
+mod persistent_map;
+mod permission;
+mod topic;
+mod whox;
+#[cfg(feature = "serde")]
+mod serialize;
 
 
 use std::default::Default;
+use std::mem;
 use std::collections::{
-    hash_map,
+    BTreeSet,
     HashMap,
     HashSet,
+    VecDeque,
 };
 use std::borrow::IntoCow;
 use std::ops::Deref;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use irc::message_types::server as irc_server;
 use irc::parse::{IrcMsg, IrcMsgPrefix};
@@ -27,6 +49,59 @@ use irc::{
 };
 
 use irc_identifier::IrcIdentifier;
+use persistent_map::PersistentMap;
+use topic::TopicHistory;
+
+pub use irc_identifier::CaseMapping;
+
+pub use cap::Capabilities;
+pub use diff::StateDiff;
+pub use error::{EventError, StateError};
+pub use extban::ExtBan;
+pub use event::{NickRejection, RemovalReason, StateChange};
+pub use isupport::ISupport;
+pub use lifecycle::{DisconnectReason, Lifecycle};
+pub use mask::{matches as mask_matches, normalize as normalize_mask};
+pub use mode::{ChannelModes, ListEntry, Membership, ModeChange, ModeKind};
+pub use permission::Denial;
+pub use topic::{Topic, TOPIC_HISTORY_LEN};
+pub use whox::{WhoxQuery, WhoxReply};
+#[cfg(feature = "serde")]
+pub use serialize::SCHEMA_VERSION;
+
+fn msg_args(msg: &IrcMsg) -> Vec<String> {
+    msg.get_args().into_iter()
+        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+        .collect()
+}
+
+fn utf8_name(name: &[u8]) -> Result<&str, StateError> {
+    ::std::str::from_utf8(name).map_err(|_| StateError::NonUtf8Name(name.to_vec()))
+}
+
+/// The `!user@host` part of a prefix, empty for a bare nick.
+fn prefix_userhost(prefix: &str) -> &str {
+    match prefix.find(|ch| ch == '!' || ch == '@') {
+        Some(idx) => &prefix[idx..],
+        None => "",
+    }
+}
+
+/// An account name as sent in extended-join and ACCOUNT, where `*`
+/// means logged out.
+fn parse_account(account: &str) -> Option<&str> {
+    match account {
+        "*" | "" => None,
+        account => Some(account),
+    }
+}
+
+/// Status modes from the flags field of a WHO reply, e.g. `H*@+`.
+fn who_flag_modes(isupport: &ISupport, flags: &str) -> BTreeSet<char> {
+    flags.chars()
+        .filter_map(|ch| isupport.prefix_mode(ch))
+        .collect()
+}
 
 pub use MessageEndpoint::{
     KnownUser,
@@ -35,6 +110,7 @@ pub use MessageEndpoint::{
 };
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum MessageEndpoint {
     KnownUser(UserId),
     KnownChannel(ChannelId),
@@ -43,49 +119,138 @@ pub enum MessageEndpoint {
 }
 
 #[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct UserId(u64);
 
 
 #[derive(Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct User {
     id: UserId,
+    #[cfg_attr(feature = "serde", serde(with = "serialize::prefix"))]
     prefix: IrcMsgPrefix<'static>,
-    channels: HashSet<ChannelId>
+    channels: HashSet<ChannelId>,
+    account: Option<String>,
+    away: Option<String>,
+    realname: Option<String>,
+    ip: Option<String>,
 }
 
 impl User {
-    fn from_who(id: UserId, who: &WhoRecord) -> User {
+    fn from_prefix(id: UserId, prefix: IrcMsgPrefix<'static>) -> User {
         User {
             id: id,
-            prefix: who.get_prefix().to_owned(),
+            prefix: prefix,
             channels: Default::default(),
+            account: None,
+            away: None,
+            realname: None,
+            ip: None,
         }
     }
 
+    fn from_nick(id: UserId, nick: &str) -> User {
+        User::from_prefix(id, IrcMsgPrefix::new(nick.to_string().into_cow()))
+    }
+
+    fn from_who(id: UserId, who: &WhoRecord) -> User {
+        User::from_prefix(id, who.get_prefix().to_owned())
+    }
+
     pub fn get_nick(&self) -> &str {
         let prefix = self.prefix.as_slice();
-        match prefix.find('!') {
+        match prefix.find(|ch| ch == '!' || ch == '@') {
             Some(idx) => &prefix[0..idx],
             None => prefix
         }
     }
 
+    /// The full `nick!user@host`, or as much of it as we know.
+    pub fn get_prefix(&self) -> &str {
+        self.prefix.as_slice()
+    }
+
+    pub fn get_username(&self) -> Option<&str> {
+        let prefix = self.prefix.as_slice();
+        let start = match prefix.find('!') {
+            Some(idx) => idx + 1,
+            None => return None,
+        };
+        let end = prefix.find('@').unwrap_or(prefix.len());
+        Some(&prefix[start..end])
+    }
+
+    pub fn get_hostname(&self) -> Option<&str> {
+        let prefix = self.prefix.as_slice();
+        prefix.find('@').map(|idx| &prefix[idx + 1..])
+    }
+
+    /// The user's away message if they're away.  Away users whose
+    /// message we haven't seen have an empty one.
+    pub fn away(&self) -> Option<&str> {
+        self.away.as_ref().map(|message| &message[..])
+    }
+
+    /// The user's realname (gecos), if we've seen it.
+    pub fn realname(&self) -> Option<&str> {
+        self.realname.as_ref().map(|realname| &realname[..])
+    }
+
+    /// The `nick!user@host` masks are matched against.  Parts we don't
+    /// know are left empty, so only wildcards match them.
+    fn mask_subject(&self) -> String {
+        format!("{}!{}@{}",
+            self.get_nick(),
+            self.get_username().unwrap_or(""),
+            self.get_hostname().unwrap_or(""))
+    }
+
+    /// The user's IP address, if a WHOX reply has revealed it.
+    pub fn ip(&self) -> Option<&str> {
+        self.ip.as_ref().map(|ip| &ip[..])
+    }
+
+    /// The services account the user is logged in to, if we know it.
+    pub fn account(&self) -> Option<&str> {
+        self.account.as_ref().map(|account| &account[..])
+    }
+
     fn set_nick(&mut self, nick: &str) {
-        self.prefix = self.prefix.with_nick(nick).expect("Need nicked prefix");
+        // Users learned from NAMES may have a bare `nick` prefix.
+        let prefix = match self.prefix.as_slice().find(|ch| ch == '!' || ch == '@') {
+            Some(idx) => format!("{}{}", nick, &self.prefix.as_slice()[idx..]),
+            None => nick.to_string(),
+        };
+        self.prefix = IrcMsgPrefix::new(prefix.into_cow());
+    }
+
+    /// Our prefix with the host and, if given, the username replaced.
+    fn prefix_with_host(&self, username: Option<&str>, hostname: &str) -> IrcMsgPrefix<'static> {
+        let prefix = match username.or(self.get_username()) {
+            Some(username) => format!("{}!{}@{}", self.get_nick(), username, hostname),
+            None => format!("{}@{}", self.get_nick(), hostname),
+        };
+        IrcMsgPrefix::new(prefix.into_cow())
     }
 }
 
 
 #[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ChannelId(u64);
 
 
 #[derive(Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Channel {
     id: ChannelId,
     name: String,
-    topic: String,
-    users: HashSet<UserId>
+    topic: TopicHistory,
+    users: PersistentMap<UserId, Membership>,
+    modes: ChannelModes,
+    created_at: Option<u64>,
+    url: Option<String>,
+    joined_at: u64,
 }
 
 impl Channel {
@@ -93,14 +258,76 @@ impl Channel {
         Channel {
             id: chan_info.id,
             name: chan_info.name.clone(),
-            topic: chan_info.topic.clone(),
+            topic: TopicHistory::new(chan_info.topic.clone()),
             users: Default::default(),
+            modes: Default::default(),
+            created_at: None,
+            url: None,
+            joined_at: chan_info.joined_at,
         }
     }
 
-    fn set_topic(&mut self, topic: &str) {
-        self.topic.clear();
-        self.topic.push_str(topic);
+    pub fn get_modes(&self) -> &ChannelModes {
+        &self.modes
+    }
+
+    pub fn has_mode(&self, mode: char) -> bool {
+        self.modes.has_mode(mode)
+    }
+
+    pub fn key(&self) -> Option<&str> {
+        self.modes.key()
+    }
+
+    pub fn limit(&self) -> Option<u32> {
+        self.modes.limit()
+    }
+
+    pub fn bans(&self) -> Vec<&ListEntry> {
+        self.modes.list('b')
+    }
+
+    pub fn exceptions(&self) -> Vec<&ListEntry> {
+        self.modes.list('e')
+    }
+
+    pub fn invite_exceptions(&self) -> Vec<&ListEntry> {
+        self.modes.list('I')
+    }
+
+    /// Quiets, on servers where `q` is a list mode.
+    pub fn quiets(&self) -> Vec<&ListEntry> {
+        self.modes.list('q')
+    }
+
+    pub fn get_member(&self, uid: UserId) -> Option<&Membership> {
+        self.users.get(&uid)
+    }
+
+    /// When the channel was created (RPL_CREATIONTIME), in unix seconds.
+    /// A channel that is recreated gets a new creation time.
+    pub fn created_at(&self) -> Option<u64> {
+        self.created_at
+    }
+
+    /// The channel's website, as sent by services (328).
+    pub fn url(&self) -> Option<&str> {
+        self.url.as_ref().map(|url| &url[..])
+    }
+
+    /// When we joined, in unix seconds.
+    pub fn joined_at(&self) -> u64 {
+        self.joined_at
+    }
+
+    pub fn topic(&self) -> &Topic {
+        self.topic.current()
+    }
+
+    /// Topics this channel had before the current one, oldest first and
+    /// at most `TOPIC_HISTORY_LEN` of them.
+    pub fn topic_history(&self) -> Vec<&Topic> {
+        self.topic.previous()
     }
 }
 
@@ -108,27 +335,48 @@ impl Channel {
 struct ChannelInfo {
     id: ChannelId,
     name: String,
-    topic: String
+    topic: Topic,
+    joined_at: u64,
 }
 
 impl ChannelInfo {
-    fn from_join(id: ChannelId, join: &JoinSuccess) -> ChannelInfo {
-        let topic = String::from_utf8(match join.topic {
-            Some(ref topic) => topic.text.clone(),
-            None => Vec::new()
-        }).ok().expect("non-string");
+    fn from_join(id: ChannelId, join: &JoinSuccess, joined_at: u64) -> Result<ChannelInfo, StateError> {
+        let topic = match join.topic {
+            Some(ref topic) => Topic {
+                text: String::from_utf8_lossy(&topic.text).into_owned(),
+                set_by: Some(topic.set_by.clone()),
+                set_at: Some(topic.set_at),
+            },
+            None => Topic::default(),
+        };
 
-        let channel_name = ::std::str::from_utf8(join.channel.as_slice())
-            .ok().expect("bad chan").to_string();
+        let channel_name = try!(utf8_name(&join.channel)).to_string();
 
-        ChannelInfo {
+        Ok(ChannelInfo {
             id: id,
             name: channel_name,
-            topic: topic
-        }
+            topic: topic,
+            joined_at: joined_at,
+        })
     }
 }
 
+#[derive(Debug, Clone)]
+struct PendingMetadata {
+    // The channel name as sent.
+    name: String,
+    created_at: Option<u64>,
+    url: Option<String>,
+}
+
+// Our prefix and account from a login (900/901) made before RPL_WELCOME
+// created our user.
+#[derive(Debug, Clone)]
+struct PendingLogin {
+    prefix: Option<String>,
+    account: Option<String>,
+}
+
 pub struct FrozenState(State);
 
 impl Deref for FrozenState {
@@ -140,6 +388,13 @@ impl Deref for FrozenState {
     }
 }
 
+impl FrozenState {
+    /// Lists what changed between `older` and this snapshot.
+    pub fn diff(&self, older: &FrozenState) -> StateDiff {
+        diff::diff(older, self)
+    }
+}
+
 unsafe impl Send for FrozenState {}
 unsafe impl Sync for FrozenState {}
 
@@ -150,14 +405,58 @@ pub struct State {
     user_seq: u64,
     channel_seq: u64,
 
+    lifecycle: Lifecycle,
+
+    // Channels we've joined and not left, kept across reconnects.
+    desired_channels: Arc<BTreeSet<String>>,
+
     self_nick: String,
     self_id: UserId,
 
-    user_map: HashMap<IrcIdentifier, UserId>,
-    users: HashMap<UserId, User>,
+    // A nick we've asked for that the server hasn't accepted or refused yet.
+    pending_nick: Option<String>,
+
+    // A SASL login, until RPL_WELCOME.
+    pending_login: Option<PendingLogin>,
+
+    // Persistent maps, shared with snapshots: a change copies only the
+    // entry it touches and the trie nodes above it.
+    user_map: PersistentMap<IrcIdentifier, UserId>,
+    users: PersistentMap<UserId, User>,
+
+    channel_map: PersistentMap<IrcIdentifier, ChannelId>,
+    channels: PersistentMap<ChannelId, Channel>,
+
+    // NAMES replies received so far, keyed by channel, until RPL_ENDOFNAMES.
+    // Channel names are kept as sent, to refold them if CASEMAPPING
+    // changes.
+    names_pending: HashMap<IrcIdentifier, (String, Vec<String>)>,
+
+    // List mode entries received so far, by channel and mode, until the
+    // end-of-list reply.
+    lists_pending: HashMap<(IrcIdentifier, char), (String, Vec<ListEntry>)>,
 
-    channel_map: HashMap<IrcIdentifier, ChannelId>,
-    channels: HashMap<ChannelId, Channel>,
+    // Channel metadata for channels we've seen our JOIN for but haven't
+    // had the JoinBundle for yet.
+    metadata_pending: HashMap<IrcIdentifier, PendingMetadata>,
+
+    // Changes made by the event currently being handled.
+    pending_changes: Vec<StateChange>,
+
+    // Shared with snapshots until they change.
+    isupport: Arc<ISupport>,
+
+    caps: Arc<Capabilities>,
+
+    // The server-time of the message being handled, if the caller gave one.
+    message_time: Option<u64>,
+
+    // Why the last connection ended.
+    last_disconnect_reason: Option<DisconnectReason>,
+
+    // WHOX queries we've built commands for and the masks they were
+    // sent for, oldest first, until their RPL_ENDOFWHO.
+    whox_queries: VecDeque<(String, WhoxQuery)>,
 
     generation: u64,
 }
@@ -167,117 +466,259 @@ impl State {
         State {
             user_seq: 1,
             channel_seq: 0,
+            lifecycle: Lifecycle::Unregistered,
+            desired_channels: Arc::new(BTreeSet::new()),
             self_nick: String::new(),
             user_map: Default::default(),
             users: Default::default(),
             self_id: UserId(0),
+            pending_nick: None,
+            pending_login: None,
             channel_map: Default::default(),
             channels: Default::default(),
+            names_pending: Default::default(),
+            metadata_pending: Default::default(),
+            lists_pending: Default::default(),
+            pending_changes: Vec::new(),
+            isupport: Arc::new(ISupport::new()),
+            caps: Arc::new(Capabilities::new()),
+            message_time: None,
+            last_disconnect_reason: None,
+            whox_queries: VecDeque::new(),
             generation: 0,
         }
     }
 
-    fn on_other_part(&mut self, part: &irc_server::Part) {
-        let channel_name = IrcIdentifier::from_str(part.get_channel());
-        let user_nick = IrcIdentifier::from_str(part.get_nick());
+    fn on_other_part(&mut self, part: &irc_server::Part) -> Result<(), StateError> {
+        let chan_id = try!(self.require_channel(part.get_channel()));
+        let user_id = try!(self.require_user(part.get_nick()));
 
-        let opt_chan_id = self.channel_map.get(&channel_name).and_then(|&v| Some(v));
-        if opt_chan_id.is_none() {
-            warn!("Got channel {:?} without knowing about it.", part.get_channel());
-        }
+        self.validate_state_internal_panic();
+        try!(self.unlink_user_channel(user_id, chan_id, RemovalReason::Part));
+        self.validate_state_internal_panic();
+        Ok(())
+    }
 
-        let opt_user_id = self.user_map.get(&user_nick).and_then(|&v| Some(v));
-        if opt_user_id.is_none() {
-            warn!("Got user {:?} without knowing about it.", part.get_nick());
-        }
+    fn on_self_part(&mut self, part: &irc_server::Part) -> Result<(), StateError> {
+        try!(self.remove_channel_by_name(part.get_channel()));
+        self.forget_desired_channel(part.get_channel());
+        Ok(())
+    }
 
-        let (chan_id, user_id) = match (opt_chan_id, opt_user_id) {
-            (Some(chan_id), Some(user_id)) => (chan_id, user_id),
-            _ => return,
-        };
+    fn forget_desired_channel(&mut self, name: &str) {
+        let name = self.identifier(name);
+        let desired = self.desired_channels.iter()
+            .filter(|other| self.identifier(other) != name)
+            .cloned()
+            .collect();
+        self.desired_channels = Arc::new(desired);
+    }
 
-        self.validate_state_internal_panic();
-        self.unlink_user_channel(user_id, chan_id);
-        self.validate_state_internal_panic();
+    fn on_self_quit(&mut self, msg: &IrcMsg) -> Result<(), StateError> {
+        // :nick!user@host QUIT :<reason>
+        let reason = msg_args(msg).pop().unwrap_or_else(String::new);
+        self.disconnected(DisconnectReason::Quit(reason));
+        Ok(())
     }
 
-    fn on_self_part(&mut self, part: &irc_server::Part) {
-        assert!(self.remove_channel_by_name(part.get_channel()).is_some());
+    fn on_kill(&mut self, msg: &IrcMsg) -> Result<(), StateError> {
+        // :killer KILL <nick> :<reason>
+        let args = msg_args(msg);
+        if args.len() < 1 {
+            return Ok(());
+        }
+        let reason = args.get(1).cloned().unwrap_or_else(String::new);
+        if self.identify_nick(&args[0]) == Some(self.self_id) {
+            self.disconnected(DisconnectReason::Killed {
+                by: msg.get_prefix().as_slice().to_string(),
+                reason: reason,
+            });
+            return Ok(());
+        }
+        // Servers normally turn KILLs of others into QUITs.
+        try!(self.remove_user_by_nick(&args[0], RemovalReason::Quit));
+        Ok(())
     }
 
-    fn on_other_quit(&mut self, quit: &irc_server::Quit) {
-        assert!(self.remove_user_by_nick(quit.get_nick()).is_some());
+    fn on_error(&mut self, msg: &IrcMsg) -> Result<(), StateError> {
+        // ERROR :Closing Link: <host> (<reason>)
+        let reason = msg_args(msg).pop().unwrap_or_else(String::new);
+        self.disconnected(DisconnectReason::Error(reason));
+        Ok(())
     }
 
-    fn on_other_join(&mut self, join: &irc_server::Join) {
-        let channel_name = IrcIdentifier::from_str(join.get_channel());
-        let user_nick = IrcIdentifier::from_str(join.get_nick());
+    fn on_other_quit(&mut self, quit: &irc_server::Quit) -> Result<(), StateError> {
+        try!(self.remove_user_by_nick(quit.get_nick(), RemovalReason::Quit));
+        Ok(())
+    }
 
-        let chan_id = match self.channel_map.get(&channel_name) {
-            Some(chan_id) => *chan_id,
-            None => panic!("Got message for channel {:?} without knowing about it.", channel_name)
-        };
+    fn on_other_join(&mut self, join: &irc_server::Join) -> Result<(), StateError> {
+        let chan_id = try!(self.require_channel(join.get_channel()));
 
-        let (is_create, user_id) = match self.user_map.get(&user_nick) {
-            Some(user_id) => {
-                (false, *user_id)
-            },
+        let user_id = match self.identify_nick(join.get_nick()) {
+            Some(user_id) => user_id,
             None => {
                 let new_user_id = UserId(self.user_seq);
                 self.user_seq += 1;
-                (true, new_user_id)
+                try!(self.insert_user(User::from_prefix(
+                    new_user_id, join.to_irc_msg().get_prefix().to_owned())));
+                new_user_id
             }
         };
-        if is_create {
-            let user = User {
-                id: user_id,
-                prefix: join.to_irc_msg().get_prefix().to_owned(),
-                channels: HashSet::new(),
-            };
-            self.users.insert(user_id, user);
-            self.user_map.insert(user_nick, user_id);
-        }
-        self.users.get_mut(&user_id).expect("user not found").channels.insert(chan_id);
+        self.link_user_channel(user_id, chan_id, None)
+    }
 
-        assert!(self.update_channel_by_name(channel_name.as_slice(), |channel| {
-            channel.users.insert(user_id);
-        }), "Got message for channel {:?} without knowing about it.");
+    fn on_extended_join(&mut self, msg: &IrcMsg) -> Result<(), StateError> {
+        // With extended-join:
+        // :nick!user@host JOIN <channel> <account or *> :<realname>
+        let args = msg_args(msg);
+        if !self.has_cap("extended-join") || args.len() < 3 {
+            return Ok(());
+        }
+        let user_id = match msg.get_prefix().nick() {
+            Some(nick) => try!(self.require_user(nick)),
+            None => return Ok(()),
+        };
+        self.set_account(user_id, parse_account(&args[1]));
+        self.set_realname(user_id, &args[2]);
+        Ok(())
     }
 
-    fn on_self_join(&mut self, join: &JoinSuccess) {
-        let channel_name = ::std::str::from_utf8(join.channel.as_slice()).ok().unwrap();
-        let channel_name = IrcIdentifier::from_str(channel_name);
+    fn on_self_join(&mut self, join: &JoinSuccess) -> Result<(), StateError> {
+        let channel_name = try!(utf8_name(&join.channel));
+        // 328 and 329 may arrive before the join completes.
+        let metadata = self.metadata_pending.remove(&self.identifier(channel_name));
 
-        if let Some(_) = self.channel_map.get(&channel_name) {
-            warn!("Joining already joined channel {:?}; skipped", join.channel);
-            return;
+        if let Some(_) = self.identify_channel(channel_name) {
+            warn!("Joining already joined channel {:?}; skipped", channel_name);
+            return Ok(());
         }
-        warn!("users = {:?}", join.nicks);
         let new_chan_id = ChannelId(self.channel_seq);
+        let chan_info = try!(ChannelInfo::from_join(new_chan_id, join, self.now()));
         self.channel_seq += 1;
 
-        self.channels.insert(new_chan_id, Channel::from_info(
-            &ChannelInfo::from_join(new_chan_id, join)));
-        self.channel_map.insert(channel_name.clone(), new_chan_id);
+        let channel_name = self.identifier(channel_name);
+        let mut channel = Channel::from_info(&chan_info);
+        if let Some(metadata) = metadata {
+            channel.created_at = metadata.created_at;
+            channel.url = metadata.url;
+        }
+        self.channels.insert(new_chan_id, channel);
+        self.channel_map.insert(channel_name, new_chan_id);
+        self.forget_desired_channel(&chan_info.name);
+        Arc::make_mut(&mut self.desired_channels).insert(chan_info.name.clone());
+        self.emit(StateChange::ChannelJoined(new_chan_id));
+        self.apply_names(new_chan_id, &join.nicks)
+    }
+
+    fn on_names_reply(&mut self, msg: &IrcMsg) -> Result<(), StateError> {
+        // :server 353 <nick> <symbol> <channel> :[prefix]<nick> [[prefix]<nick> ...]
+        let args = msg_args(msg);
+        if args.len() < 4 {
+            return Ok(());
+        }
+        let channel_name = self.identifier(&args[2]);
+        let &mut (_, ref mut names) = self.names_pending.entry(channel_name)
+            .or_insert_with(|| (args[2].clone(), Vec::new()));
+        names.extend(args[3].split(' ').filter(|n| !n.is_empty()).map(|n| n.to_string()));
+        Ok(())
+    }
+
+    fn on_names_end(&mut self, msg: &IrcMsg) -> Result<(), StateError> {
+        // :server 366 <nick> <channel> :End of /NAMES list.
+        let args = msg_args(msg);
+        if args.len() < 2 {
+            return Ok(());
+        }
+        let channel_name = self.identifier(&args[1]);
+        let names = match self.names_pending.remove(&channel_name) {
+            Some((_, names)) => names,
+            None => return Ok(()),
+        };
+        // NAMES for a channel being joined is delivered again in the
+        // JoinBundle, once the channel exists.
+        match self.identify_channel(&args[1]) {
+            Some(chan_id) => self.apply_names(chan_id, &names),
+            None => Ok(()),
+        }
+    }
+
+    /// Links every user in a NAMES list to the channel, creating users
+    /// we haven't seen yet and refreshing status modes for the rest.
+    fn apply_names(&mut self, chan_id: ChannelId, names: &[String]) -> Result<(), StateError> {
+        let multi_prefix = self.has_cap("multi-prefix");
+        let userhost_in_names = self.has_cap("userhost-in-names");
+        for name in names.iter() {
+            let entry = names::parse_entry(&self.isupport, name, multi_prefix, userhost_in_names);
+            if entry.nick.is_empty() {
+                continue;
+            }
+            let prefix = entry.prefix.unwrap_or(entry.nick);
+            let prefix = IrcMsgPrefix::new(prefix.to_string().into_cow());
+            let user_id = match self.identify_nick(entry.nick) {
+                Some(user_id) => {
+                    if entry.prefix.is_some() {
+                        self.set_user_prefix(user_id, prefix);
+                    }
+                    user_id
+                },
+                None => {
+                    let new_user_id = UserId(self.user_seq);
+                    self.user_seq += 1;
+                    try!(self.insert_user(User::from_prefix(new_user_id, prefix)));
+                    new_user_id
+                }
+            };
+            let modes = self.refreshed_status(chan_id, user_id, entry.modes);
+            try!(self.link_user_channel(user_id, chan_id, modes));
+        }
+        self.validate_state_internal_panic();
+        Ok(())
+    }
+
+    /// The status modes to store for a member given those a NAMES entry
+    /// or WHO reply shows.  Without multi-prefix these only show the
+    /// highest status, so what we know is kept if that still matches.
+    fn refreshed_status(&self, chan_id: ChannelId, user_id: UserId,
+                        modes: BTreeSet<char>) -> Option<BTreeSet<char>> {
+        if !self.has_cap("multi-prefix") && self.highest_status(chan_id, user_id) == modes {
+            None
+        } else {
+            Some(modes)
+        }
+    }
+
+    /// A member's highest-ranked status mode, as a set for comparison
+    /// with a single-prefix NAMES entry.
+    fn highest_status(&self, chan_id: ChannelId, user_id: UserId) -> BTreeSet<char> {
+        let member = match self.channels.get(&chan_id).and_then(|chan| chan.users.get(&user_id)) {
+            Some(member) => member,
+            None => return BTreeSet::new(),
+        };
+        self.isupport.prefixes().iter()
+            .map(|&(mode, _)| mode)
+            .filter(|&mode| member.has_mode(mode))
+            .take(1)
+            .collect()
     }
 
-    fn validate_state_with_who(&self, who: &WhoSuccess) {
-        let channel_name = ::std::str::from_utf8(who.channel.as_slice()).ok().unwrap();
-        let channel_name = IrcIdentifier::from_str(channel_name);
+    fn validate_state_with_who(&self, who: &WhoSuccess) -> Result<(), StateError> {
+        let channel_name = try!(utf8_name(&who.channel));
 
-        let (_, channel) = match self.get_channel_by_name(channel_name.as_slice()) {
+        let (_, channel) = match try!(self.get_channel_by_name(channel_name)) {
             Some(chan_pair) => chan_pair,
-            None => return
+            None => return Ok(())
         };
 
         info!("Validating channel state");
         let mut known_users = HashSet::new();
-        for user in channel.users.iter() {
-            match self.users.get(user) {
+        for user_id in channel.users.keys() {
+            match self.users.get(user_id) {
                 Some(user) => {
                     known_users.insert(user.get_nick().to_string());
                 },
-                None => panic!("Inconsistent state"),
+                None => return Err(StateError::Inconsistent(
+                    format!("{:?} refs non-existent {:?}", channel.id, user_id))),
             }
         }
 
@@ -302,369 +743,1495 @@ impl State {
         } else {
             warn!("Channel state has been validated: desynchronized!");
         }
+        Ok(())
     }
 
-    fn on_who(&mut self, who: &WhoSuccess) {
+    fn on_who(&mut self, who: &WhoSuccess) -> Result<(), StateError> {
         // If we WHO a channel that we aren't in, we aren't changing any
         // state.
-        let channel_name = ::std::str::from_utf8(who.channel.as_slice()).ok().unwrap();
-        let channel_name = IrcIdentifier::from_str(channel_name);
-
-        let chan_id = match self.get_channel_by_name(channel_name.as_slice()) {
-            Some((chan_id, channel)) => {
-                if !channel.users.is_empty() {
-                    self.validate_state_with_who(who);
-                    return;
+        let channel_name = try!(utf8_name(&who.channel));
+        if !self.isupport.is_channel_name(channel_name) {
+            // WHO on a nick, usually our own: only per-user details change.
+            for rec in who.who_records.iter() {
+                if let Some(user_id) = self.identify_nick(&rec.nick) {
+                    self.update_user_from_who(user_id, rec);
                 }
-                chan_id
             }
-            None => return
-        };
+            return Ok(());
+        }
 
-        let mut users = Vec::with_capacity(who.who_records.len());
-        let mut user_ids = Vec::with_capacity(who.who_records.len());
+        let (chan_id, populated) = match try!(self.get_channel_by_name(channel_name)) {
+            Some((chan_id, channel)) => (chan_id, !channel.users.is_empty()),
+            None => return Ok(())
+        };
+        if populated {
+            try!(self.validate_state_with_who(who));
+            return self.update_members_from_who(chan_id, who);
+        }
 
+        let mut added = 0;
         for rec in who.who_records.iter() {
-            let nick = IrcIdentifier::from_str(&rec.nick);
-            user_ids.push(match self.user_map.get(&nick) {
-                Some(user_id) => *user_id,
+            let user_id = match self.identify_nick(&rec.nick) {
+                Some(user_id) => user_id,
                 None => {
                     let new_user_id = UserId(self.user_seq);
                     self.user_seq += 1;
-                    users.push(User::from_who(new_user_id, rec));
+                    try!(self.insert_user(User::from_who(new_user_id, rec)));
                     new_user_id
                 }
-            });
-        }
-        for user in users.into_iter() {
-            self.insert_user(user);
-        }
-        for user_id in user_ids.iter() {
-            match self.users.get_mut(user_id) {
-                Some(user_state) => {
-                    user_state.channels.insert(chan_id);
-                },
-                None => {
-                    if *user_id != self.self_id {
-                        panic!("{:?}", user_id);
-                    }
-                }
             };
+            self.update_user_from_who(user_id, rec);
+            let modes = who_flag_modes(&self.isupport, &rec.flags);
+            try!(self.link_user_channel(user_id, chan_id, Some(modes)));
+            added += 1;
         }
-
-        let tmp_chan_name = channel_name.clone();
-        assert!(self.update_channel_by_name(channel_name.as_slice(), move |channel| {
-            let added = user_ids.len() - channel.users.len();
-            info!("Added {:?} users for channel {:?}", added, tmp_chan_name);
-            channel.users.extend(user_ids.into_iter());
-        }), "Got message for channel {:?} without knowing about it.");
-    }
-
-    fn on_topic(&mut self, topic: &irc_server::Topic) {
-        assert!(self.update_channel_by_name(topic.get_channel(), |channel| {
-            let topic = String::from_utf8_lossy(topic.get_body_raw()).into_owned();
-            channel.set_topic(&topic);
-        }));
+        info!("Added {:?} users for channel {:?}", added, channel_name);
+        Ok(())
     }
 
-    fn on_nick(&mut self, nick: &irc_server::Nick) {
-        assert!(self.update_user_by_nick(nick.get_nick(), |user| {
-            user.set_nick(nick.get_new_nick());
-        }))
+    fn on_topic(&mut self, topic: &irc_server::Topic) -> Result<(), StateError> {
+        let chan_id = try!(self.require_channel(topic.get_channel()));
+        let topic = Topic {
+            text: String::from_utf8_lossy(topic.get_body_raw()).into_owned(),
+            set_by: Some(topic.to_irc_msg().get_prefix().as_slice().to_string()),
+            set_at: Some(self.now()),
+        };
+        self.set_channel_topic(chan_id, topic);
+        Ok(())
     }
 
-    //
-    fn on_kick(&mut self, kick: &irc_server::Kick) {
-        let channel_name = IrcIdentifier::from_str(kick.get_channel());
-        let kicked_user_nick = IrcIdentifier::from_str(kick.get_kicked_nick());
-
-        let (chan_id, user_id) = match (
-            self.channel_map.get(&channel_name),
-            self.user_map.get(&kicked_user_nick)
-        ) {
-            (Some(chan_id), Some(user_id)) => (*chan_id, *user_id),
-            (None, Some(_)) => {
-                warn!("Strange: unknown channel {:?}", channel_name);
-                return;
-            },
-            (Some(_), None) => {
-                warn!("Strange: unknown nick {:?}", kicked_user_nick);
-                return;
-            },
-            (None, None) => {
-                warn!("Strange: unknown chan {:?} and nick {:?}", channel_name, kicked_user_nick);
-                return;
-            }
+    fn on_rpl_topic(&mut self, msg: &IrcMsg) -> Result<(), StateError> {
+        // :server 332 <nick> <channel> :<topic>
+        // :server 331 <nick> <channel> :No topic is set
+        let args = msg_args(msg);
+        if args.len() < 3 {
+            return Ok(());
+        }
+        // Replies while joining arrive before the channel exists and are
+        // delivered again in the JoinBundle.
+        let chan_id = match self.identify_channel(&args[1]) {
+            Some(chan_id) => chan_id,
+            None => return Ok(()),
         };
-        self.unlink_user_channel(user_id, chan_id);
+        let text = if msg.get_command() == "332" { args[2].clone() } else { String::new() };
+        let unchanged = self.channels[&chan_id].topic().text == text;
+        if !unchanged {
+            self.set_channel_topic(chan_id, Topic {
+                text: text,
+                set_by: None,
+                set_at: None,
+            });
+        }
+        Ok(())
     }
 
-    pub fn is_self_join(&self, msg: &IrcMsg) -> Option<irc_server::Join> {
-        use irc::message_types::server::IncomingMsg::Join;
-
-        let is_self = msg.get_prefix().nick().and_then(|nick| {
-            Some(nick == self.self_nick)
-        }).unwrap_or(false);
-
-        if !is_self {
-            return None;
+    fn on_topic_who_time(&mut self, msg: &IrcMsg) -> Result<(), StateError> {
+        // :server 333 <nick> <channel> <setter> <unix time>
+        let args = msg_args(msg);
+        if args.len() < 4 {
+            return Ok(());
         }
-        match irc_server::IncomingMsg::from_msg(msg.clone()) {
-            Join(join) => Some(join),
-            _ => None,
+        let chan_id = match self.identify_channel(&args[1]) {
+            Some(chan_id) => chan_id,
+            None => return Ok(()),
+        };
+        let set_at = args[3].parse().ok();
+        let mut changed = false;
+        self.update_channel(chan_id, |channel| {
+            changed = channel.topic.set_metadata(&args[2], set_at);
+        });
+        if changed {
+            self.emit(StateChange::TopicChanged(chan_id));
         }
+        Ok(())
     }
 
-    pub fn on_message(&mut self, msg: &IrcMsg) {
-        use irc::message_types::server::IncomingMsg::{
-            Part, Quit, Join, Topic, Kick, Nick};
-
-        let ty_msg = irc_server::IncomingMsg::from_msg(msg.clone());
-        let is_self = msg.get_prefix().nick().and_then(|nick| {
-            Some(nick == self.self_nick)
-        }).unwrap_or(false);
-
-        match (&ty_msg, is_self) {
-            (&Part(ref part), true) => return self.on_self_part(part),
-            (&Part(ref part), false) => return self.on_other_part(part),
-            (&Quit(ref quit), false) => return self.on_other_quit(quit),
-            // is this JOIN right?
-            (&Join(ref join), false) => return self.on_other_join(join),
-            (&Topic(ref topic), _) => return self.on_topic(topic),
-            (&Nick(ref nick), _) => return self.on_nick(nick),
-            (&Kick(ref kick), _) => return self.on_kick(kick),
-            (_, _) => ()
+    fn on_creation_time(&mut self, msg: &IrcMsg) -> Result<(), StateError> {
+        // :server 329 <nick> <channel> <unix time>
+        let args = msg_args(msg);
+        if args.len() < 3 {
+            return Ok(());
         }
-
-        if msg.get_command() == "001" {
-            let channel_name = ::std::str::from_utf8(&msg[0]).ok().unwrap();
-            self.initialize_self_nick(channel_name);
+        let created_at = match args[2].parse() {
+            Ok(created_at) => created_at,
+            Err(_) => return Ok(()),
+        };
+        let chan_id = match self.identify_channel(&args[1]) {
+            Some(chan_id) => chan_id,
+            None => {
+                // Replies to queries about channels we aren't joining,
+                // such as MODE, are dropped.
+                let channel_name = self.identifier(&args[1]);
+                if let Some(metadata) = self.metadata_pending.get_mut(&channel_name) {
+                    metadata.created_at = Some(created_at);
+                }
+                return Ok(());
+            }
+        };
+        let previous = self.channels[&chan_id].created_at;
+        if previous == Some(created_at) {
+            return Ok(());
+        }
+        if let Some(previous) = previous {
+            warn!("{:?} recreated: creation time {} is now {}", chan_id, previous, created_at);
         }
+        self.update_channel(chan_id, |channel| {
+            channel.created_at = Some(created_at);
+        });
+        self.emit(StateChange::ChannelMetadataChanged(chan_id));
+        Ok(())
     }
 
-    pub fn on_event(&mut self, event: &IrcEvent) {
-        let () = match *event {
-            IrcEvent::IrcMsg(ref message) => self.on_message(message),
-            IrcEvent::JoinBundle(Ok(ref join_bun)) => self.on_self_join(join_bun),
-            IrcEvent::JoinBundle(Err(_)) => (),
-            IrcEvent::WhoBundle(Ok(ref who_bun)) => self.on_who(who_bun),
-            IrcEvent::WhoBundle(Err(_)) => (),
-            IrcEvent::Extension(_) => {
-                unimplemented!();
+    fn on_channel_url(&mut self, msg: &IrcMsg) -> Result<(), StateError> {
+        // :server 328 <nick> <channel> :<url>
+        let args = msg_args(msg);
+        if args.len() < 3 {
+            return Ok(());
+        }
+        let url = args[2].clone();
+        let chan_id = match self.identify_channel(&args[1]) {
+            Some(chan_id) => chan_id,
+            None => {
+                let channel_name = self.identifier(&args[1]);
+                if let Some(metadata) = self.metadata_pending.get_mut(&channel_name) {
+                    metadata.url = Some(url);
+                }
+                return Ok(());
             }
         };
+        if self.channels[&chan_id].url() == Some(&url[..]) {
+            return Ok(());
+        }
+        self.update_channel(chan_id, |channel| {
+            channel.url = Some(url);
+        });
+        self.emit(StateChange::ChannelMetadataChanged(chan_id));
+        Ok(())
     }
 
-    pub fn get_self_nick<'a>(&'a self) -> &'a str {
-        &self.self_nick
+    fn set_channel_topic(&mut self, chan_id: ChannelId, topic: Topic) {
+        let mut changed = false;
+        self.update_channel(chan_id, |channel| {
+            changed = channel.topic.set(topic);
+        });
+        if changed {
+            self.emit(StateChange::TopicChanged(chan_id));
+        }
     }
 
-    pub fn set_self_nick(&mut self, new_nick_str: &str) {
-        let new_nick = IrcIdentifier::from_str(new_nick_str);
-        let old_nick = IrcIdentifier::from_str(&self.self_nick);
-        if self.self_nick != "" {
-            let user_id = match self.user_map.remove(&old_nick) {
-                Some(user_id) => user_id,
-                None => panic!("inconsistent user_map: {:?}[{:?}]",
-                    self.user_map, self.self_nick)
-            };
-            self.user_map.insert(new_nick, user_id);
+    fn on_nick(&mut self, nick: &irc_server::Nick) -> Result<(), StateError> {
+        let user_id = try!(self.require_user(nick.get_nick()));
+        if let Some(other_id) = self.identify_nick(nick.get_new_nick()) {
+            if other_id != user_id {
+                return Err(StateError::Inconsistent(format!(
+                    "{:?} renamed to {:?}, held by {:?}",
+                    user_id, nick.get_new_nick(), other_id)));
+            }
         }
-        self.self_nick = new_nick_str.to_string();
+        let old_nick = self.users[&user_id].get_nick().to_string();
+        self.update_user(user_id, |user| {
+            user.set_nick(nick.get_new_nick());
+        });
+        self.validate_state_internal_panic();
+        self.emit(StateChange::NickChanged {
+            user: user_id,
+            old: old_nick,
+            new: nick.get_new_nick().to_string(),
+        });
+        Ok(())
     }
 
-    fn initialize_self_nick(&mut self, new_nick_str: &str) {
-        let new_nick = IrcIdentifier::from_str(new_nick_str);
-        self.user_map.insert(new_nick, self.self_id);
-        self.users.insert(self.self_id, User {
-            id: self.self_id,
-            // FIXME: hack
-            prefix: IrcMsgPrefix::new(format!("{}!someone@somewhere", new_nick_str).into_cow()),
-            channels: HashSet::new(),
-        });
-        self.set_self_nick(new_nick_str);
+    fn on_self_nick(&mut self, nick: &irc_server::Nick) -> Result<(), StateError> {
+        try!(self.on_nick(nick));
+        self.self_nick = nick.get_new_nick().to_string();
+        self.pending_nick = None;
+        Ok(())
     }
 
-    fn unlink_user_channel(&mut self, uid: UserId, chid: ChannelId) {
-        let should_remove = match self.users.entry(uid) {
-            hash_map::Entry::Occupied(mut entry) => {
-                if entry.get().channels.len() == 1 && entry.get().channels.contains(&chid) {
-                    true
-                } else {
-                    entry.get_mut().channels.remove(&chid);
-                    false
-                }
-            }
-            hash_map::Entry::Vacant(_) => panic!("Inconsistent state")
+    fn on_nick_rejected(&mut self, msg: &IrcMsg, reason: NickRejection) -> Result<(), StateError> {
+        // :server 433 <current nick or *> <nick> :Nickname is already in use
+        let args = msg_args(msg);
+        if args.len() < 2 {
+            return Ok(());
+        }
+        let nick = args[1].clone();
+        let is_pending = match self.pending_nick {
+            Some(ref pending) => self.identifier(pending) == self.identifier(&nick),
+            None => false,
         };
-        if should_remove {
-            warn!("removing {:?}", uid);
-            self.remove_user_by_id(uid);
+        if is_pending {
+            self.pending_nick = None;
         }
-
-        let should_remove = match self.channels.entry(chid) {
-            hash_map::Entry::Occupied(mut entry) => {
-                if entry.get().users.len() == 1 && entry.get().users.contains(&uid) {
-                    true
-                } else {
-                    entry.get_mut().users.remove(&uid);
-                    false
+        self.emit(StateChange::NickRejected { nick: nick, reason: reason });
+        Ok(())
+    }
+
+    fn on_welcome(&mut self, msg: &IrcMsg) -> Result<(), StateError> {
+        // :server 001 <nick> :Welcome to the Internet Relay Network <prefix>
+        let nick = try!(utf8_name(&msg[0]));
+        if self.lifecycle == Lifecycle::Registered {
+            warn!("RPL_WELCOME while registered; assuming we reconnected");
+            self.disconnected(DisconnectReason::ConnectionLost);
+            self.set_lifecycle(Lifecycle::Unregistered);
+        }
+        self.set_lifecycle(Lifecycle::Registered);
+        try!(self.initialize_self_nick(nick));
+        if let Some(login) = self.pending_login.take() {
+            // The prefix is stale if we registered under another nick.
+            let prefix = match login.prefix {
+                Some(ref prefix) if prefix.starts_with(&format!("{}!", nick)) => Some(&prefix[..]),
+                _ => None,
+            };
+            self.set_self_login(prefix, login.account.as_ref().map(|account| &account[..]));
+        }
+
+        // Most servers end the welcome text with our full prefix.
+        let args = msg_args(msg);
+        let last_word = args.last().and_then(|text| text.split(' ').last());
+        if let Some(prefix) = last_word {
+            if prefix.starts_with(&format!("{}!", nick)) && prefix.contains('@') {
+                let self_id = self.self_id;
+                self.set_user_prefix(self_id, IrcMsgPrefix::new(prefix.to_string().into_cow()));
+            }
+        }
+        Ok(())
+    }
+
+    fn on_self_prefix(&mut self, msg: &IrcMsg) -> Result<(), StateError> {
+        let prefix = msg.get_prefix().to_owned();
+        if prefix.as_slice().contains('@') {
+            let self_id = self.self_id;
+            self.set_user_prefix(self_id, prefix);
+        }
+        Ok(())
+    }
+
+    fn on_host_hidden(&mut self, msg: &IrcMsg) -> Result<(), StateError> {
+        // :server 396 <nick> <host> :is now your displayed host
+        // Some servers send `<user>@<host>` instead.
+        let args = msg_args(msg);
+        if args.len() < 2 {
+            return Ok(());
+        }
+        let (username, hostname) = match args[1].find('@') {
+            Some(idx) => (Some(&args[1][..idx]), &args[1][idx + 1..]),
+            None => (None, &args[1][..]),
+        };
+        let self_id = self.self_id;
+        self.set_user_host(self_id, username, hostname);
+        Ok(())
+    }
+
+    fn on_userhost(&mut self, msg: &IrcMsg) -> Result<(), StateError> {
+        // :server 302 <nick> :<nick>[*]=<+|-><user>@<host> [...]
+        let args = msg_args(msg);
+        if args.len() < 2 {
+            return Ok(());
+        }
+        for reply in args[1].split(' ') {
+            let (nick, hostmask) = match reply.find('=') {
+                Some(idx) => (
+                    reply[..idx].trim_right_matches('*'),
+                    reply[idx + 1..].trim_left_matches(|ch| ch == '+' || ch == '-'),
+                ),
+                None => continue,
+            };
+            if let Some(user_id) = self.identify_nick(nick) {
+                let prefix = format!("{}!{}", nick, hostmask);
+                self.set_user_prefix(user_id, IrcMsgPrefix::new(prefix.into_cow()));
+            }
+        }
+        Ok(())
+    }
+
+    fn on_account(&mut self, msg: &IrcMsg) -> Result<(), StateError> {
+        // :nick!user@host ACCOUNT <account or *>
+        let args = msg_args(msg);
+        if args.len() < 1 {
+            return Ok(());
+        }
+        let user_id = match msg.get_prefix().nick() {
+            Some(nick) => try!(self.require_user(nick)),
+            None => return Ok(()),
+        };
+        self.set_account(user_id, parse_account(&args[0]));
+        Ok(())
+    }
+
+    fn on_logged_in(&mut self, msg: &IrcMsg) -> Result<(), StateError> {
+        // :server 900 <nick> <nick!user@host> <account> :You are now logged in as <account>
+        let args = msg_args(msg);
+        if args.len() < 3 {
+            return Ok(());
+        }
+        let prefix = if args[1].contains('@') { Some(&args[1][..]) } else { None };
+        self.set_self_login(prefix, Some(&args[2]));
+        Ok(())
+    }
+
+    fn on_logged_out(&mut self, msg: &IrcMsg) -> Result<(), StateError> {
+        // :server 901 <nick> <nick!user@host> :You are now logged out
+        let args = msg_args(msg);
+        let prefix = if args.len() > 2 && args[1].contains('@') { Some(&args[1][..]) } else { None };
+        self.set_self_login(prefix, None);
+        Ok(())
+    }
+
+    fn set_self_login(&mut self, prefix: Option<&str>, account: Option<&str>) {
+        let self_id = self.self_id;
+        if !self.users.contains_key(&self_id) {
+            // SASL completes before registration; we're added by 001.
+            self.pending_login = Some(PendingLogin {
+                prefix: prefix.map(|prefix| prefix.to_string()),
+                account: account.map(|account| account.to_string()),
+            });
+            return;
+        }
+        if let Some(prefix) = prefix {
+            self.set_user_prefix(self_id, IrcMsgPrefix::new(prefix.to_string().into_cow()));
+        }
+        self.set_account(self_id, account);
+    }
+
+    fn on_setname(&mut self, msg: &IrcMsg) -> Result<(), StateError> {
+        // :nick!user@host SETNAME :<realname>
+        let args = msg_args(msg);
+        if args.len() < 1 {
+            return Ok(());
+        }
+        let user_id = match msg.get_prefix().nick() {
+            Some(nick) => try!(self.require_user(nick)),
+            None => return Ok(()),
+        };
+        self.set_realname(user_id, &args[0]);
+        Ok(())
+    }
+
+    /// Applies the IRCv3 `account` tag of a message sent by `nick`.
+    ///
+    /// The message parser doesn't expose tags, so callers that read them
+    /// pass the value through here.
+    pub fn on_account_tag(&mut self, nick: &str, account: &str) -> Result<Vec<StateChange>, EventError> {
+        let result = match self.identify_nick(nick) {
+            Some(user_id) => {
+                self.set_account(user_id, parse_account(account));
+                Ok(())
+            },
+            None => Ok(()),
+        };
+        self.take_changes(result)
+    }
+
+    fn on_away(&mut self, msg: &IrcMsg) -> Result<(), StateError> {
+        // With away-notify:
+        // :nick!user@host AWAY [:<message>]
+        let args = msg_args(msg);
+        let user_id = match msg.get_prefix().nick() {
+            Some(nick) => try!(self.require_user(nick)),
+            None => return Ok(()),
+        };
+        self.set_away(user_id, args.get(0).map(|message| &message[..]));
+        Ok(())
+    }
+
+    fn on_rpl_away(&mut self, msg: &IrcMsg) -> Result<(), StateError> {
+        // :server 301 <nick> <away nick> :<message>
+        let args = msg_args(msg);
+        if args.len() < 3 {
+            return Ok(());
+        }
+        // We get these for anyone we message, tracked or not.
+        if let Some(user_id) = self.identify_nick(&args[1]) {
+            self.set_away(user_id, Some(&args[2]));
+        }
+        Ok(())
+    }
+
+    fn on_self_away(&mut self, away: bool) -> Result<(), StateError> {
+        // 305 RPL_UNAWAY / 306 RPL_NOWAWAY; neither repeats our message.
+        let self_id = self.self_id;
+        let message = match self.users.get(&self_id) {
+            Some(user) => user.away().unwrap_or("").to_string(),
+            None => return Ok(()),
+        };
+        self.set_away(self_id, if away { Some(&message) } else { None });
+        Ok(())
+    }
+
+    fn on_chghost(&mut self, msg: &IrcMsg) -> Result<(), StateError> {
+        // :nick!user@host CHGHOST <new user> <new host>
+        let args = msg_args(msg);
+        if args.len() < 2 {
+            return Ok(());
+        }
+        let nick = match msg.get_prefix().nick() {
+            Some(nick) => nick.to_string(),
+            None => return Ok(()),
+        };
+        let user_id = try!(self.require_user(&nick));
+        self.set_user_host(user_id, Some(&args[0]), &args[1]);
+        Ok(())
+    }
+
+    fn on_cap(&mut self, msg: &IrcMsg) -> Result<(), StateError> {
+        // :server CAP <nick or *> <subcommand> [*] :<caps>
+        // where `*` marks a multi-line LS or LIST reply.
+        let args = msg_args(msg);
+        if args.len() < 3 {
+            return Ok(());
+        }
+        if self.lifecycle == Lifecycle::Unregistered {
+            self.set_lifecycle(Lifecycle::CapNegotiation);
+        }
+        let subcommand = args[1].to_ascii_uppercase();
+        if Arc::make_mut(&mut self.caps).apply(&subcommand, &args[args.len() - 1]) {
+            self.emit(StateChange::CapabilitiesChanged);
+        }
+        Ok(())
+    }
+
+    fn on_kick(&mut self, kick: &irc_server::Kick) -> Result<(), StateError> {
+        let chan_id = try!(self.require_channel(kick.get_channel()));
+        let user_id = try!(self.require_user(kick.get_kicked_nick()));
+        if user_id == self.self_id {
+            // We stay interested in the channel, unlike after a PART.
+            self.remove_channel_by_id(chan_id);
+            return Ok(());
+        }
+        self.unlink_user_channel(user_id, chan_id, RemovalReason::Kick)
+    }
+
+    fn on_mode(&mut self, msg: &IrcMsg) -> Result<(), StateError> {
+        // :nick!user@host MODE <target> <modes> [params...]
+        let args = msg_args(msg);
+        if args.len() < 2 || !self.isupport.is_channel_name(&args[0]) {
+            return Ok(());
+        }
+        let chan_id = try!(self.require_channel(&args[0]));
+        let changes = mode::parse_mode_changes(
+            &args[1], &args[2..], |m| self.isupport.mode_kind(m));
+
+        // Targets we don't know are skipped so the rest of the line still
+        // applies; the first is reported once it has.
+        let mut member_changes = Vec::new();
+        let mut error = None;
+        for change in changes.iter().filter(|c| c.kind == ModeKind::Prefix) {
+            let nick = match change.arg {
+                Some(ref nick) => nick,
+                None => continue,
+            };
+            let user_id = match self.require_user(nick) {
+                Ok(user_id) => user_id,
+                Err(err) => {
+                    error = error.or(Some(err));
+                    continue;
+                }
+            };
+            if self.membership(chan_id, user_id).is_none() {
+                error = error.or(Some(StateError::Inconsistent(format!(
+                    "mode {:?} for {:?}, not in {:?}", change.mode, user_id, chan_id))));
+                continue;
+            }
+            member_changes.push((user_id, change));
+        }
+
+        let set_by = msg.get_prefix().as_slice().to_string();
+        let set_at = self.now();
+        let mut emitted = Vec::new();
+        self.update_channel(chan_id, |channel| {
+            for change in changes.iter() {
+                if channel.modes.apply_from(change, Some(&set_by), Some(set_at)) {
+                    emitted.push(StateChange::ModeChanged {
+                        channel: chan_id,
+                        change: change.clone(),
+                    });
+                }
+            }
+            for &(user_id, change) in member_changes.iter() {
+                if let Some(member) = channel.users.get_mut(&user_id) {
+                    if member.apply(change) {
+                        emitted.push(StateChange::MemberModesChanged {
+                            channel: chan_id,
+                            user: user_id,
+                        });
+                    }
                 }
+            }
+        });
+        for change in emitted.into_iter() {
+            self.emit(change);
+        }
+        match error {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    fn on_list_entry(&mut self, msg: &IrcMsg, mode: char) -> Result<(), StateError> {
+        // :server 367 <nick> <channel> <mask> [<setter> <unix time>]
+        let args = msg_args(msg);
+        if args.len() < 3 {
+            return Ok(());
+        }
+        self.add_pending_list_entry(mode, &args[1..])
+    }
+
+    fn on_list_end(&mut self, msg: &IrcMsg, mode: char) -> Result<(), StateError> {
+        // :server 368 <nick> <channel> :End of channel ban list
+        let args = msg_args(msg);
+        if args.len() < 2 {
+            return Ok(());
+        }
+        self.finish_pending_list(mode, &args[1])
+    }
+
+    fn on_quiet_list(&mut self, msg: &IrcMsg) -> Result<(), StateError> {
+        // :server 728 <nick> <channel> <mode> <mask> [<setter> <unix time>]
+        // :server 729 <nick> <channel> <mode> :End of channel quiet list
+        let args = msg_args(msg);
+        if args.len() < 3 {
+            return Ok(());
+        }
+        let mode = match args[2].chars().next() {
+            Some(mode) => mode,
+            None => return Ok(()),
+        };
+        if msg.get_command() == "728" {
+            let mut entry_args = vec![args[1].clone()];
+            entry_args.extend(args[3..].iter().cloned());
+            self.add_pending_list_entry(mode, &entry_args)
+        } else {
+            self.finish_pending_list(mode, &args[1])
+        }
+    }
+
+    /// `args` is `<channel> <mask> [<setter> <unix time>]`.
+    fn add_pending_list_entry(&mut self, mode: char, args: &[String]) -> Result<(), StateError> {
+        if args.len() < 2 || self.isupport.mode_kind(mode) != ModeKind::List {
+            return Ok(());
+        }
+        let entry = ListEntry {
+            mask: args[1].clone(),
+            set_by: args.get(2).cloned(),
+            set_at: args.get(3).and_then(|time| time.parse().ok()),
+        };
+        let key = (self.identifier(&args[0]), mode);
+        let &mut (_, ref mut entries) = self.lists_pending.entry(key)
+            .or_insert_with(|| (args[0].clone(), Vec::new()));
+        entries.push(entry);
+        Ok(())
+    }
+
+    fn finish_pending_list(&mut self, mode: char, channel_name: &str) -> Result<(), StateError> {
+        if self.isupport.mode_kind(mode) != ModeKind::List {
+            return Ok(());
+        }
+        let key = (self.identifier(channel_name), mode);
+        let entries = match self.lists_pending.remove(&key) {
+            Some((_, entries)) => entries,
+            None => Vec::new(),
+        };
+        // We can list channels we aren't in; there's nothing to keep then.
+        let chan_id = match self.identify_channel(channel_name) {
+            Some(chan_id) => chan_id,
+            None => return Ok(()),
+        };
+        let mut changed = false;
+        self.update_channel(chan_id, |channel| {
+            changed = channel.modes.replace_list(mode, entries);
+        });
+        if changed {
+            self.emit(StateChange::ModeListChanged {
+                channel: chan_id,
+                mode: mode,
+            });
+        }
+        Ok(())
+    }
+
+    fn update_members_from_who(&mut self, chan_id: ChannelId, who: &WhoSuccess) -> Result<(), StateError> {
+        let mut updates = Vec::with_capacity(who.who_records.len());
+        for rec in who.who_records.iter() {
+            if let Some(user_id) = self.identify_nick(&rec.nick) {
+                self.update_user_from_who(user_id, rec);
+                let modes = who_flag_modes(&self.isupport, &rec.flags);
+                if let Some(modes) = self.refreshed_status(chan_id, user_id, modes) {
+                    updates.push((user_id, modes));
+                }
+            }
+        }
+        let mut changed = Vec::new();
+        self.update_channel(chan_id, |channel| {
+            for (user_id, modes) in updates.into_iter() {
+                if let Some(member) = channel.users.get_mut(&user_id) {
+                    if member.set_modes(modes) {
+                        changed.push(user_id);
+                    }
+                }
+            }
+        });
+        for user_id in changed.into_iter() {
+            self.emit(StateChange::MemberModesChanged {
+                channel: chan_id,
+                user: user_id,
+            });
+        }
+        Ok(())
+    }
+
+    fn update_user_from_who(&mut self, user_id: UserId, rec: &WhoRecord) {
+        // Users learned from NAMES only have a nick so far.
+        self.set_user_prefix(user_id, rec.get_prefix().to_owned());
+        self.set_realname(user_id, &rec.realname);
+        self.set_away_from_who_flags(user_id, &rec.flags);
+    }
+
+    fn set_away_from_who_flags(&mut self, user_id: UserId, flags: &str) {
+        // `H` is here, `G` is gone; WHO doesn't carry the away message.
+        if flags.starts_with('G') {
+            let message = match self.users.get(&user_id) {
+                Some(user) => user.away().unwrap_or("").to_string(),
+                None => return,
+            };
+            self.set_away(user_id, Some(&message));
+        } else if flags.starts_with('H') {
+            self.set_away(user_id, None);
+        }
+    }
+
+    fn on_whox_reply(&mut self, msg: &IrcMsg) -> Result<(), StateError> {
+        // :server 354 <nick> <token> [<field> ...]
+        let args = msg_args(msg);
+        if args.len() < 2 {
+            return Ok(());
+        }
+        // Replies come in the order the queries were sent, so a reused
+        // token belongs to the oldest query still running.
+        let query = self.whox_queries.iter()
+            .map(|&(_, ref query)| query)
+            .find(|query| query.token().to_string() == args[1])
+            .cloned();
+        let query = match query {
+            Some(query) => query,
+            None => return Ok(()),
+        };
+        let reply = match query.parse_reply(&args[1..]) {
+            Some(reply) => reply,
+            None => {
+                warn!("Malformed WHOX reply for token {}: {:?}", query.token(), args);
+                return Ok(());
+            }
+        };
+        let nick = match reply.nick {
+            Some(nick) => nick,
+            None => return Ok(()),
+        };
+        let chan_id = reply.channel.and_then(|name| self.identify_channel(name));
+
+        let prefix = match (reply.username, reply.hostname) {
+            (Some(username), Some(hostname)) => format!("{}!{}@{}", nick, username, hostname),
+            _ => nick.to_string(),
+        };
+        let prefix = IrcMsgPrefix::new(prefix.into_cow());
+        let user_id = match (self.identify_nick(nick), chan_id) {
+            (Some(user_id), _) => {
+                if reply.username.is_some() && reply.hostname.is_some() {
+                    self.set_user_prefix(user_id, prefix);
+                }
+                user_id
+            },
+            // Only members of channels we're in are tracked.
+            (None, Some(_)) => {
+                let new_user_id = UserId(self.user_seq);
+                self.user_seq += 1;
+                try!(self.insert_user(User::from_prefix(new_user_id, prefix)));
+                new_user_id
+            },
+            (None, None) => return Ok(()),
+        };
+
+        if let Some(realname) = reply.realname {
+            self.set_realname(user_id, realname);
+        }
+        if let Some(account) = reply.account {
+            self.set_account(user_id, if account == "0" { None } else { Some(account) });
+        }
+        if let Some(ip) = reply.ip {
+            self.set_ip(user_id, ip);
+        }
+        if let Some(flags) = reply.flags {
+            self.set_away_from_who_flags(user_id, flags);
+        }
+        if let Some(chan_id) = chan_id {
+            let modes = reply.flags.and_then(|flags| {
+                self.refreshed_status(chan_id, user_id, who_flag_modes(&self.isupport, flags))
+            });
+            try!(self.link_user_channel(user_id, chan_id, modes));
+        }
+        Ok(())
+    }
+
+    /// The command to send to run `query` against `mask`, remembering the
+    /// query so its replies can be read.  Falls back to a plain WHO if
+    /// the server doesn't advertise WHOX.
+    pub fn who_command(&mut self, mask: &str, query: &WhoxQuery) -> String {
+        if self.isupport.get("WHOX").is_none() {
+            return format!("WHO {}", mask);
+        }
+        self.whox_queries.push_back((mask.to_string(), query.clone()));
+        query.command(mask)
+    }
+
+    fn on_who_end(&mut self, msg: &IrcMsg) -> Result<(), StateError> {
+        // :server 315 <nick> <mask> :End of WHO list
+        let args = msg_args(msg);
+        if args.len() < 2 {
+            return Ok(());
+        }
+        let mask = self.identifier(&args[1]);
+        let finished = self.whox_queries.iter()
+            .position(|&(ref sent_for, _)| self.identifier(sent_for) == mask);
+        if let Some(idx) = finished {
+            self.whox_queries.remove(idx);
+        }
+        Ok(())
+    }
+
+    fn on_channel_mode_is(&mut self, msg: &IrcMsg) -> Result<(), StateError> {
+        // :server 324 <nick> <channel> <modes> [params...]
+        let args = msg_args(msg);
+        if args.len() < 3 {
+            return Ok(());
+        }
+        // We can query the modes of channels we aren't in.
+        let chan_id = match self.identify_channel(&args[1]) {
+            Some(chan_id) => chan_id,
+            None => return Ok(()),
+        };
+        let changes = mode::parse_mode_changes(
+            &args[2], &args[3..], |m| self.isupport.mode_kind(m));
+
+        let effective = {
+            let isupport = &self.isupport;
+            match self.channels.get_mut(&chan_id) {
+                Some(channel) => channel.modes
+                    .replace_settings(&changes, |m| isupport.mode_kind(m)),
+                None => Vec::new(),
+            }
+        };
+        for change in effective.into_iter() {
+            self.emit(StateChange::ModeChanged {
+                channel: chan_id,
+                change: change,
+            });
+        }
+        Ok(())
+    }
+
+    fn on_isupport(&mut self, msg: &IrcMsg) -> Result<(), StateError> {
+        // :server 005 <nick> <token> [<token> ...] :are supported by this server
+        let args = msg_args(msg);
+        if args.len() < 3 {
+            return Ok(());
+        }
+        let prev_casemapping = self.isupport.casemapping();
+        for token in args[1..args.len() - 1].iter() {
+            Arc::make_mut(&mut self.isupport).apply_token(token);
+        }
+        self.generation += 1;
+        if prev_casemapping != self.isupport.casemapping() {
+            info!("Casemapping changed from {:?} to {:?}",
+                prev_casemapping, self.isupport.casemapping());
+            self.rebuild_identifier_maps();
+        }
+        Ok(())
+    }
+
+    /// Refolds every name under the current casemapping.  Names that
+    /// now fold together can't both be tracked; we keep ourselves and
+    /// otherwise the oldest, and remove the rest.
+    fn rebuild_identifier_maps(&mut self) {
+        let self_id = self.self_id;
+        let mut user_ids: Vec<UserId> = self.users.keys().cloned().collect();
+        user_ids.sort_by_key(|&user_id| (user_id != self_id, user_id));
+        let mut user_map = PersistentMap::new();
+        let mut user_collisions = Vec::new();
+        for user_id in user_ids.into_iter() {
+            let nick = self.identifier(self.users[&user_id].get_nick());
+            match user_map.get(&nick) {
+                Some(&other_id) => user_collisions.push((other_id, user_id)),
+                None => { user_map.insert(nick, user_id); },
+            }
+        }
+
+        let mut chan_ids: Vec<ChannelId> = self.channels.keys().cloned().collect();
+        chan_ids.sort();
+        let mut channel_map = PersistentMap::new();
+        let mut channel_collisions = Vec::new();
+        for chan_id in chan_ids.into_iter() {
+            let name = self.identifier(&self.channels[&chan_id].name);
+            match channel_map.get(&name) {
+                Some(&other_id) => channel_collisions.push((other_id, chan_id)),
+                None => { channel_map.insert(name, chan_id); },
+            }
+        }
+
+        self.user_map = user_map;
+        self.channel_map = channel_map;
+        for (other_id, chan_id) in channel_collisions.into_iter() {
+            warn!("Channel collision under new casemapping: {:?} and {:?}", other_id, chan_id);
+            self.remove_channel_by_id(chan_id);
+        }
+        for (other_id, user_id) in user_collisions.into_iter() {
+            // Removing a channel may have taken the user with it.
+            if self.users.contains_key(&user_id) {
+                warn!("Nick collision under new casemapping: {:?} and {:?}", other_id, user_id);
+                let _ = self.remove_user_by_id(user_id, RemovalReason::Part);
+            }
+        }
+        self.refold_pending();
+        self.validate_state_internal_panic();
+    }
+
+    fn refold_pending(&mut self) {
+        let casemapping = self.isupport.casemapping();
+        self.names_pending = self.names_pending.drain()
+            .map(|(_, (name, names))| (IrcIdentifier::new(&name, casemapping), (name, names)))
+            .collect();
+        self.lists_pending = self.lists_pending.drain()
+            .map(|((_, mode), (name, entries))| {
+                ((IrcIdentifier::new(&name, casemapping), mode), (name, entries))
+            })
+            .collect();
+        self.metadata_pending = self.metadata_pending.drain()
+            .map(|(_, metadata)| (IrcIdentifier::new(&metadata.name, casemapping), metadata))
+            .collect();
+    }
+
+    pub fn is_self_join(&self, msg: &IrcMsg) -> Option<irc_server::Join> {
+        use irc::message_types::server::IncomingMsg::Join;
+
+        let is_self = msg.get_prefix().nick().and_then(|nick| {
+            Some(nick == self.self_nick)
+        }).unwrap_or(false);
+
+        if !is_self {
+            return None;
+        }
+        match irc_server::IncomingMsg::from_msg(msg.clone()) {
+            Join(join) => Some(join),
+            _ => None,
+        }
+    }
+
+    /// Applies a message, returning the changes it caused.
+    pub fn on_message(&mut self, msg: &IrcMsg) -> Result<Vec<StateChange>, EventError> {
+        let result = self.handle_message(msg);
+        self.take_changes(result)
+    }
+
+    /// Applies a message carrying an IRCv3 `server-time` tag, given here
+    /// in unix seconds since the message parser doesn't expose tags.
+    /// The time is used instead of our clock for anything the message
+    /// timestamps, such as topics.
+    pub fn on_message_at(&mut self, msg: &IrcMsg, time: u64) -> Result<Vec<StateChange>, EventError> {
+        self.message_time = Some(time);
+        let result = self.handle_message(msg);
+        self.message_time = None;
+        self.take_changes(result)
+    }
+
+    fn handle_message(&mut self, msg: &IrcMsg) -> Result<(), StateError> {
+        use irc::message_types::server::IncomingMsg::{
+            Part, Quit, Join, Topic, Kick, Nick};
+
+        if self.lifecycle == Lifecycle::Disconnected {
+            // Servers follow our QUIT or a KILL with ERROR on the same
+            // connection, which mustn't look like a new one.
+            if msg.get_command() == "ERROR" {
+                return Ok(());
+            }
+            self.set_lifecycle(Lifecycle::Unregistered);
+        }
+
+        let ty_msg = irc_server::IncomingMsg::from_msg(msg.clone());
+        let is_self = msg.get_prefix().nick().and_then(|nick| {
+            Some(nick == self.self_nick)
+        }).unwrap_or(false);
+
+        match (&ty_msg, is_self) {
+            (&Part(ref part), true) => return self.on_self_part(part),
+            (&Part(ref part), false) => return self.on_other_part(part),
+            (&Quit(_), true) => return self.on_self_quit(msg),
+            (&Quit(ref quit), false) => return self.on_other_quit(quit),
+            // is this JOIN right?
+            (&Join(ref join), false) => {
+                try!(self.on_other_join(join));
+                return self.on_extended_join(msg);
+            },
+            // The channel itself is tracked from the JoinBundle.
+            (&Join(ref join), true) => {
+                let channel_name = self.identifier(join.get_channel());
+                self.metadata_pending.insert(channel_name, PendingMetadata {
+                    name: join.get_channel().to_string(),
+                    created_at: None,
+                    url: None,
+                });
+                try!(self.on_self_prefix(msg));
+                return self.on_extended_join(msg);
             },
-            hash_map::Entry::Vacant(_) => panic!("Inconsistent state")
+            (&Topic(ref topic), _) => return self.on_topic(topic),
+            (&Nick(ref nick), true) => return self.on_self_nick(nick),
+            (&Nick(ref nick), false) => return self.on_nick(nick),
+            (&Kick(ref kick), _) => return self.on_kick(kick),
+            (_, _) => ()
+        }
+
+        match msg.get_command() {
+            "001" => self.on_welcome(msg),
+            "CAP" => self.on_cap(msg),
+            "ERROR" => self.on_error(msg),
+            "KILL" => self.on_kill(msg),
+            "005" => self.on_isupport(msg),
+            "302" => self.on_userhost(msg),
+            "432" => self.on_nick_rejected(msg, NickRejection::Erroneous),
+            "433" => self.on_nick_rejected(msg, NickRejection::InUse),
+            "436" => self.on_nick_rejected(msg, NickRejection::Collision),
+            "324" => self.on_channel_mode_is(msg),
+            "346" => self.on_list_entry(msg, 'I'),
+            "347" => self.on_list_end(msg, 'I'),
+            "348" => self.on_list_entry(msg, 'e'),
+            "349" => self.on_list_end(msg, 'e'),
+            "367" => self.on_list_entry(msg, 'b'),
+            "368" => self.on_list_end(msg, 'b'),
+            "728" => self.on_quiet_list(msg),
+            "729" => self.on_quiet_list(msg),
+            "331" | "332" => self.on_rpl_topic(msg),
+            "328" => self.on_channel_url(msg),
+            "329" => self.on_creation_time(msg),
+            "333" => self.on_topic_who_time(msg),
+            "353" => self.on_names_reply(msg),
+            "315" => self.on_who_end(msg),
+            "354" => self.on_whox_reply(msg),
+            "366" => self.on_names_end(msg),
+            "396" => self.on_host_hidden(msg),
+            "900" => self.on_logged_in(msg),
+            "901" => self.on_logged_out(msg),
+            "301" => self.on_rpl_away(msg),
+            "305" => self.on_self_away(false),
+            "306" => self.on_self_away(true),
+            "ACCOUNT" => self.on_account(msg),
+            "AWAY" => self.on_away(msg),
+            "CHGHOST" => self.on_chghost(msg),
+            "SETNAME" => self.on_setname(msg),
+            "MODE" => self.on_mode(msg),
+            _ => Ok(())
+        }
+    }
+
+    /// Applies an event, returning the changes it caused.  An event that
+    /// can't be applied in full may still have changed the state; the
+    /// error carries those changes.
+    pub fn on_event(&mut self, event: &IrcEvent) -> Result<Vec<StateChange>, EventError> {
+        let result = match *event {
+            IrcEvent::IrcMsg(ref message) => self.handle_message(message),
+            IrcEvent::JoinBundle(Ok(ref join_bun)) => self.on_self_join(join_bun),
+            IrcEvent::JoinBundle(Err(_)) => Ok(()),
+            IrcEvent::WhoBundle(Ok(ref who_bun)) => self.on_who(who_bun),
+            IrcEvent::WhoBundle(Err(_)) => Ok(()),
+            IrcEvent::Extension(_) => Ok(()),
         };
-        if should_remove {
+        self.take_changes(result)
+    }
+
+    /// Forgets everything tied to the current connection: channels,
+    /// users, our nick and any half-received replies.  Server parameters
+    /// and `desired_channels` are kept, and the next message applied
+    /// starts a new connection.
+    pub fn on_disconnect(&mut self) -> Vec<StateChange> {
+        if self.lifecycle != Lifecycle::Disconnected {
+            self.disconnected(DisconnectReason::ConnectionLost);
+        }
+        mem::replace(&mut self.pending_changes, Vec::new())
+    }
+
+    fn disconnected(&mut self, reason: DisconnectReason) {
+        info!("Disconnected: {:?}", reason);
+        self.last_disconnect_reason = Some(reason);
+        self.reset_connection();
+    }
+
+    /// Why the most recent connection ended, kept until the next one does.
+    pub fn last_disconnect_reason(&self) -> Option<&DisconnectReason> {
+        self.last_disconnect_reason.as_ref()
+    }
+
+    fn reset_connection(&mut self) {
+        let mut parted: Vec<(ChannelId, String)> = self.channels.values()
+            .map(|channel| (channel.id, channel.name.clone()))
+            .collect();
+        parted.sort();
+        for (chan_id, name) in parted.into_iter() {
+            self.emit(StateChange::ChannelParted { channel: chan_id, name: name });
+        }
+        let mut removed: Vec<(UserId, String)> = self.users.values()
+            .map(|user| (user.id, user.get_nick().to_string()))
+            .collect();
+        removed.sort();
+        for (user_id, nick) in removed.into_iter() {
+            self.emit(StateChange::UserRemoved { user: user_id, nick: nick });
+        }
+
+        self.user_map = Default::default();
+        self.users = Default::default();
+        self.channel_map = Default::default();
+        self.channels = Default::default();
+        self.names_pending.clear();
+        self.metadata_pending.clear();
+        self.lists_pending.clear();
+        self.caps = Arc::new(Capabilities::new());
+        self.whox_queries.clear();
+        self.self_nick = String::new();
+        self.pending_nick = None;
+        self.pending_login = None;
+        self.set_lifecycle(Lifecycle::Disconnected);
+        self.validate_state_internal_panic();
+    }
+
+    /// Whether a capability has been acknowledged on this connection.
+    pub fn has_cap(&self, name: &str) -> bool {
+        self.caps.is_enabled(name)
+    }
+
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.caps
+    }
+
+    pub fn lifecycle(&self) -> Lifecycle {
+        self.lifecycle
+    }
+
+    /// Channels we've joined and haven't parted, including those we were
+    /// in when the connection was lost.
+    pub fn desired_channels(&self) -> Vec<&str> {
+        self.desired_channels.iter().map(|name| &name[..]).collect()
+    }
+
+    fn set_lifecycle(&mut self, lifecycle: Lifecycle) {
+        if self.lifecycle != lifecycle {
+            self.lifecycle = lifecycle;
+            self.emit(StateChange::LifecycleChanged(lifecycle));
+        }
+    }
+
+    fn emit(&mut self, change: StateChange) {
+        self.generation += 1;
+        self.pending_changes.push(change);
+    }
+
+    fn take_changes(&mut self, result: Result<(), StateError>) -> Result<Vec<StateChange>, EventError> {
+        let changes = mem::replace(&mut self.pending_changes, Vec::new());
+        match result {
+            Ok(()) => Ok(changes),
+            Err(error) => Err(EventError { error: error, changes: changes }),
+        }
+    }
+
+    /// The current time in unix seconds, preferring the message's
+    /// server-time.
+    fn now(&self) -> u64 {
+        match self.message_time {
+            Some(time) => time,
+            None => SystemTime::now().duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or(0),
+        }
+    }
+
+    fn identifier(&self, val: &str) -> IrcIdentifier {
+        IrcIdentifier::new(val, self.isupport.casemapping())
+    }
+
+    fn require_channel(&self, name: &str) -> Result<ChannelId, StateError> {
+        match self.identify_channel(name) {
+            Some(chan_id) => Ok(chan_id),
+            None => Err(StateError::UnknownChannel(name.to_string())),
+        }
+    }
+
+    fn require_user(&self, nick: &str) -> Result<UserId, StateError> {
+        match self.identify_nick(nick) {
+            Some(user_id) => Ok(user_id),
+            None => Err(StateError::UnknownUser(nick.to_string())),
+        }
+    }
+
+    pub fn get_self_nick<'a>(&'a self) -> &'a str {
+        &self.self_nick
+    }
+
+    /// Our own user, with the most accurate prefix the server has told
+    /// us.  `None` until registered.
+    pub fn self_user(&self) -> Option<&User> {
+        if self.self_nick == "" {
+            return None;
+        }
+        self.users.get(&self.self_id)
+    }
+
+    /// Renames our own user, e.g. after sending a NICK the server won't
+    /// echo back, returning the changes.  Fails if the nick belongs to
+    /// another tracked user.
+    pub fn set_self_nick(&mut self, new_nick_str: &str) -> Result<Vec<StateChange>, EventError> {
+        let result = self.rename_self(new_nick_str);
+        self.take_changes(result)
+    }
+
+    fn rename_self(&mut self, new_nick_str: &str) -> Result<(), StateError> {
+        if self.self_nick != "" {
+            let self_id = self.self_id;
+            if let Some(other_id) = self.identify_nick(new_nick_str) {
+                if other_id != self_id {
+                    return Err(StateError::Inconsistent(format!(
+                        "self renamed to {:?}, held by {:?}", new_nick_str, other_id)));
+                }
+            }
+            let old_nick = match self.users.get(&self_id) {
+                Some(user) => user.get_nick().to_string(),
+                None => return Err(StateError::Inconsistent(format!(
+                    "self nick {:?} missing from users", self.self_nick))),
+            };
+            self.update_user(self_id, |user| user.set_nick(new_nick_str));
+            if old_nick != new_nick_str {
+                self.emit(StateChange::NickChanged {
+                    user: self_id,
+                    old: old_nick,
+                    new: new_nick_str.to_string(),
+                });
+            }
+        }
+        self.self_nick = new_nick_str.to_string();
+        self.pending_nick = None;
+        self.generation += 1;
+        Ok(())
+    }
+
+    /// Records that we've sent `NICK <nick>`, during registration or
+    /// after it.  Cleared once the server confirms or refuses it.
+    pub fn request_nick(&mut self, nick: &str) {
+        self.pending_nick = Some(nick.to_string());
+        self.generation += 1;
+    }
+
+    /// The nick we've asked for but haven't been confirmed in yet.  Our
+    /// current nick, if registered, is `get_self_nick`.
+    pub fn pending_nick(&self) -> Option<&str> {
+        self.pending_nick.as_ref().map(|nick| &nick[..])
+    }
+
+    fn initialize_self_nick(&mut self, new_nick_str: &str) -> Result<(), StateError> {
+        let new_nick = self.identifier(new_nick_str);
+        let self_id = self.self_id;
+        self.user_map.insert(new_nick, self_id);
+        // Our user@host is filled in as the server tells us about it.
+        self.users.insert(self_id, User::from_nick(self_id, new_nick_str));
+        self.emit(StateChange::UserAdded(self_id));
+        self.rename_self(new_nick_str)
+    }
+
+    /// Adds `uid` to `chid`, replacing its status modes if `modes` is given.
+    fn link_user_channel(&mut self, uid: UserId, chid: ChannelId,
+                         modes: Option<BTreeSet<char>>) -> Result<(), StateError> {
+        if !self.users.contains_key(&uid) || !self.channels.contains_key(&chid) {
+            return Err(StateError::Inconsistent(format!(
+                "linking {:?} to {:?}: missing endpoint", uid, chid)));
+        }
+        self.user_mut(uid).unwrap().channels.insert(chid);
+
+        let (added, modes_changed) = {
+            let channel = self.channel_mut(chid).unwrap();
+            let added = !channel.users.contains_key(&uid);
+            if added {
+                channel.users.insert(uid, Membership::default());
+            }
+            let member = channel.users.get_mut(&uid).unwrap();
+            let modes_changed = match modes {
+                Some(modes) => member.set_modes(modes),
+                None => false,
+            };
+            (added, modes_changed)
+        };
+        if added {
+            self.emit(StateChange::MemberAdded { channel: chid, user: uid });
+        } else if modes_changed {
+            self.emit(StateChange::MemberModesChanged { channel: chid, user: uid });
+        }
+        Ok(())
+    }
+
+    fn unlink_user_channel(&mut self, uid: UserId, chid: ChannelId,
+                           reason: RemovalReason) -> Result<(), StateError> {
+        if !self.users.contains_key(&uid) || !self.channels.contains_key(&chid) {
+            return Err(StateError::Inconsistent(format!(
+                "unlinking {:?} from {:?}: missing endpoint", uid, chid)));
+        }
+        if !self.channels[&chid].users.contains_key(&uid) {
+            return Err(StateError::Inconsistent(format!(
+                "unlinking {:?} from {:?}: not a member", uid, chid)));
+        }
+
+        let nick = self.users[&uid].get_nick().to_string();
+        let channel_name = self.channels[&chid].name.clone();
+        self.user_mut(uid).unwrap().channels.remove(&chid);
+        self.channel_mut(chid).unwrap().users.remove(&uid);
+        self.emit(StateChange::MemberRemoved {
+            channel: chid,
+            user: uid,
+            channel_name: channel_name,
+            nick: nick,
+            reason: reason,
+        });
+
+        if uid != self.self_id && self.users[&uid].channels.is_empty() {
+            warn!("removing {:?}", uid);
+            try!(self.remove_user_by_id(uid, reason));
+        }
+        if self.channels[&chid].users.is_empty() {
             warn!("removing {:?}", chid);
             self.remove_channel_by_id(chid);
         }
+        Ok(())
     }
+
     fn update_channel<F>(&mut self, id: ChannelId, modfunc: F) -> bool where
         F: FnOnce(&mut Channel) -> ()
     {
-        match self.channels.entry(id) {
-            hash_map::Entry::Occupied(mut entry) => {
+        match self.channel_mut(id) {
+            Some(channel) => {
                 // Channel currently has no indexed mutable state
-                modfunc(entry.get_mut());
+                modfunc(channel);
                 true
             }
-            hash_map::Entry::Vacant(_) => false
+            None => false
         }
     }
 
-    fn update_channel_by_name<F>(&mut self, name: &str, modfunc: F) -> bool
-        where
-            F: FnOnce(&mut Channel) -> () {
+    fn channel_mut(&mut self, id: ChannelId) -> Option<&mut Channel> {
+        self.channels.get_mut(&id)
+    }
 
-        let ch_name = IrcIdentifier::from_str(name);
-        if let Some(&chan_id) = self.channel_map.get(&ch_name) {
-            let result = self.update_channel(chan_id, modfunc);
-            self.validate_state_internal_panic();
-            result
-        } else {
-            warn!("Unknown channel name: {:?}", name);
-            false
-        }
+    fn user_mut(&mut self, id: UserId) -> Option<&mut User> {
+        self.users.get_mut(&id)
     }
 
-    fn remove_channel_by_name(&mut self, name: &str) -> Option<ChannelId> {
-        let ch_name = IrcIdentifier::from_str(name);
-        if let Some(&chan_id) = self.channel_map.get(&ch_name) {
-            assert!(self.remove_channel_by_id(chan_id));
-            self.validate_state_internal_panic();
-            Some(chan_id)
-        } else {
-            warn!("Unknown channel name: {:?}", name);
-            None
+    fn remove_channel_by_name(&mut self, name: &str) -> Result<ChannelId, StateError> {
+        let chan_id = try!(self.require_channel(name));
+        if !self.remove_channel_by_id(chan_id) {
+            return Err(StateError::Inconsistent(format!(
+                "channel_map[{:?}] refs non-existent {:?}", name, chan_id)));
         }
+        self.validate_state_internal_panic();
+        Ok(chan_id)
     }
 
     fn remove_channel_by_id(&mut self, id: ChannelId) -> bool {
-        let (chan_name, users): (_, Vec<_>) = match self.channels.get(&id) {
+        let (name, users): (String, Vec<_>) = match self.channels.get(&id) {
             Some(chan_state) => (
-                IrcIdentifier::from_str(&chan_state.name),
-                chan_state.users.iter().map(|x| *x).collect()
+                chan_state.name.clone(),
+                chan_state.users.keys().map(|x| *x).collect()
             ),
             None => return false
         };
+        let chan_name = self.identifier(&name);
+        self.channels.remove(&id);
+        // After a casemapping change the name may belong to another channel.
+        if self.channel_map.get(&chan_name) == Some(&id) {
+            self.channel_map.remove(&chan_name);
+        }
+        self.emit(StateChange::ChannelParted { channel: id, name: name });
+
+        let self_id = self.self_id;
+        let mut orphans = Vec::new();
         for user_id in users.into_iter() {
-            self.channels.get_mut(&id).unwrap().users.remove(&user_id);
-            self.users.get_mut(&user_id).unwrap().channels.remove(&id);
-            // self.unlink_user_channel(user_id, id);
+            if let Some(user_state) = self.user_mut(user_id) {
+                user_state.channels.remove(&id);
+                if user_state.channels.is_empty() && user_id != self_id {
+                    orphans.push(user_id);
+                }
+            }
+        }
+        // Users we only knew through this channel can't be tracked anymore.
+        for user_id in orphans.into_iter() {
+            let _ = self.remove_user_by_id(user_id, RemovalReason::Part);
         }
-        self.channels.remove(&id);
-        self.channel_map.remove(&chan_name);
         self.validate_state_internal_panic();
         true
     }
 
-    fn get_channel_by_name(&self, name: &str) -> Option<(ChannelId, &Channel)> {
-        let chan_id = match self.channel_map.get(&IrcIdentifier::from_str(name)) {
-            Some(chan_id) => *chan_id,
-            None => return None
+    fn get_channel_by_name(&self, name: &str) -> Result<Option<(ChannelId, &Channel)>, StateError> {
+        let chan_id = match self.identify_channel(name) {
+            Some(chan_id) => chan_id,
+            None => return Ok(None)
         };
         match self.channels.get(&chan_id) {
-            Some(channel) => Some((chan_id, channel)),
-            None => panic!("Inconsistent state")
+            Some(channel) => Ok(Some((chan_id, channel))),
+            None => Err(StateError::Inconsistent(format!(
+                "channel_map[{:?}] refs non-existent {:?}", name, chan_id)))
         }
     }
 
-    fn insert_user(&mut self, user: User) {
+    fn insert_user(&mut self, user: User) -> Result<(), StateError> {
         let user_id = user.id;
-        let nick = IrcIdentifier::from_str(user.prefix.nick().unwrap());
-        assert!(self.users.insert(user_id, user).is_none());
-        assert!(self.user_map.insert(nick, user_id).is_none());
+        let nick = self.identifier(user.get_nick());
+        if self.users.contains_key(&user_id) || self.user_map.contains_key(&nick) {
+            return Err(StateError::Inconsistent(format!(
+                "inserting {:?} as {:?}: already present", user_id, nick)));
+        }
+        self.users.insert(user_id, user);
+        self.user_map.insert(nick, user_id);
         self.validate_state_internal_panic();
+        self.emit(StateChange::UserAdded(user_id));
+        Ok(())
     }
 
-    fn update_user_by_nick<F>(&mut self, nick: &str, modfunc: F) -> bool where
+    fn update_user<F>(&mut self, id: UserId, modfunc: F) -> bool where
         F: FnOnce(&mut User) -> ()
     {
-        let nick = IrcIdentifier::from_str(nick);
-        if let Some(&user_id) = self.user_map.get(&nick) {
-            let result = self.update_user(user_id, modfunc);
-            self.validate_state_internal_panic();
-            result
-        } else {
-            warn!("Couldn't find user by nick: {:?}", nick);
-            false
+        let casemapping = self.isupport.casemapping();
+        let (prev_nick, new_nick) = match self.user_mut(id) {
+            Some(user) => {
+                let prev_nick = IrcIdentifier::new(user.get_nick(), casemapping);
+                modfunc(user);
+                (prev_nick, IrcIdentifier::new(user.get_nick(), casemapping))
+            }
+            None => return false
+        };
+        if prev_nick != new_nick {
+            let user_map = &mut self.user_map;
+            user_map.remove(&prev_nick);
+            user_map.insert(new_nick, id);
         }
+        true
     }
 
-    fn update_user<F>(&mut self, id: UserId, modfunc: F) -> bool where
-        F: FnOnce(&mut User) -> ()
+    /// Compares and sets one of a user's optional fields, which mustn't
+    /// be the nick, returning whether it changed.
+    fn set_user_field<G, S>(&mut self, id: UserId, value: Option<&str>, get: G, set: S) -> bool where
+        G: Fn(&User) -> Option<&str>,
+        S: FnOnce(&mut User, Option<String>) -> ()
     {
-        match self.users.entry(id) {
-            hash_map::Entry::Occupied(mut entry) => {
-                let prev_nick = IrcIdentifier::from_str(entry.get().prefix.nick().unwrap());
-                modfunc(entry.get_mut());
-                let new_nick = IrcIdentifier::from_str(entry.get().prefix.nick().unwrap());
-                warn!("prev_nick != new_nick || {:?} != {:?}", prev_nick, new_nick);
-                if prev_nick != new_nick {
-                    warn!("self.user_map -- REMOVE {:?}; INSERT {:?}", prev_nick, new_nick);
-                    self.user_map.remove(&prev_nick);
-                    self.user_map.insert(new_nick, id);
-                }
-                true
-            }
-            hash_map::Entry::Vacant(_) => false
+        match self.users.get(&id) {
+            Some(user) if get(user) != value => (),
+            _ => return false,
         }
+        set(self.user_mut(id).unwrap(), value.map(|value| value.to_string()));
+        true
     }
 
-    fn remove_user_by_nick(&mut self, name: &str) -> Option<UserId> {
-        let user_id = match self.user_map.get(&IrcIdentifier::from_str(name)) {
-            Some(user_id) => *user_id,
-            None => return None
+    fn set_account(&mut self, id: UserId, account: Option<&str>) {
+        if self.set_user_field(id, account, User::account, |user, account| user.account = account) {
+            self.emit(StateChange::AccountChanged(id));
+        }
+    }
+
+    fn set_away(&mut self, id: UserId, message: Option<&str>) {
+        if self.set_user_field(id, message, User::away, |user, message| user.away = message) {
+            self.emit(StateChange::AwayChanged(id));
+        }
+    }
+
+    fn set_realname(&mut self, id: UserId, realname: &str) {
+        let changed = self.set_user_field(
+            id, Some(realname), User::realname, |user, realname| user.realname = realname);
+        if changed {
+            self.emit(StateChange::RealnameChanged(id));
+        }
+    }
+
+    fn set_ip(&mut self, id: UserId, ip: &str) {
+        if self.set_user_field(id, Some(ip), User::ip, |user, ip| user.ip = ip) {
+            self.generation += 1;
+        }
+    }
+
+    /// Replaces a user's prefix, returning whether it changed.  The nick
+    /// must fold to the same identifier.
+    fn set_user_prefix(&mut self, id: UserId, prefix: IrcMsgPrefix<'static>) -> bool {
+        let (unchanged, host_changed) = match self.users.get(&id) {
+            Some(user) => (
+                user.prefix == prefix,
+                prefix_userhost(user.get_prefix()) != prefix_userhost(prefix.as_slice()),
+            ),
+            None => return false,
         };
-        match self.remove_user_by_id(user_id) {
-            true => Some(user_id),
-            false => panic!("Inconsistent state")
+        if unchanged {
+            return false;
+        }
+        self.user_mut(id).unwrap().prefix = prefix;
+        if host_changed {
+            self.emit(StateChange::HostChanged(id));
+        } else {
+            self.generation += 1;
         }
+        true
+    }
+
+    fn set_user_host(&mut self, id: UserId, username: Option<&str>, hostname: &str) {
+        let prefix = match self.users.get(&id) {
+            Some(user) => user.prefix_with_host(username, hostname),
+            None => return,
+        };
+        self.set_user_prefix(id, prefix);
+    }
+
+    fn remove_user_by_nick(&mut self, name: &str, reason: RemovalReason) -> Result<UserId, StateError> {
+        let user_id = try!(self.require_user(name));
+        try!(self.remove_user_by_id(user_id, reason));
+        Ok(user_id)
     }
 
-    fn remove_user_by_id(&mut self, id: UserId) -> bool {
+    fn remove_user_by_id(&mut self, id: UserId, reason: RemovalReason) -> Result<(), StateError> {
         if self.self_id == id {
-            panic!("Tried to remove self");
+            return Err(StateError::Inconsistent(format!("tried to remove self {:?}", id)));
         }
-        let (nick, channels): (_, Vec<_>) = match self.users.get(&id) {
+        let (nick, channels): (String, Vec<_>) = match self.users.get(&id) {
             Some(user_state) => (
-                IrcIdentifier::from_str(user_state.prefix.nick().unwrap()),
+                user_state.get_nick().to_string(),
                 user_state.channels.iter().map(|x| *x).collect(),
             ),
-            None => return false
+            None => return Err(StateError::Inconsistent(format!(
+                "tried to remove non-existent {:?}", id)))
         };
         for chan_id in channels.into_iter() {
-            self.channels.get_mut(&chan_id).unwrap().users.remove(&id);
-            self.users.get_mut(&id).unwrap().channels.remove(&chan_id);
+            let channel_name = match self.channel_mut(chan_id) {
+                Some(chan_state) => {
+                    chan_state.users.remove(&id);
+                    chan_state.name.clone()
+                },
+                None => continue,
+            };
+            self.emit(StateChange::MemberRemoved {
+                channel: chan_id,
+                user: id,
+                channel_name: channel_name,
+                nick: nick.clone(),
+                reason: reason,
+            });
         }
 
-        self.users.remove(&id).unwrap();
-        self.user_map.remove(&nick).unwrap();
+        let nick_id = self.identifier(&nick);
+        self.users.remove(&id);
+        if self.user_map.get(&nick_id) == Some(&id) {
+            self.user_map.remove(&nick_id);
+        }
         self.validate_state_internal_panic();
-        true
+        self.emit(StateChange::UserRemoved { user: id, nick: nick });
+        Ok(())
     }
 
     pub fn identify_channel(&self, chan: &str) -> Option<ChannelId> {
-        match self.channel_map.get(&IrcIdentifier::from_str(chan)) {
+        let chan = self.isupport.strip_statusmsg(chan);
+        match self.channel_map.get(&self.identifier(chan)) {
             Some(chan_id) => Some(chan_id.clone()),
             None => None
         }
@@ -675,7 +2242,7 @@ impl State {
     }
 
     pub fn identify_nick(&self, nick: &str) -> Option<UserId> {
-        match self.user_map.get(&IrcIdentifier::from_str(nick)) {
+        match self.user_map.get(&self.identifier(nick)) {
             Some(user_id) => Some(*user_id),
             None => None
         }
@@ -685,8 +2252,190 @@ impl State {
         self.users.get(&uid)
     }
 
+    pub fn membership(&self, chid: ChannelId, uid: UserId) -> Option<&Membership> {
+        self.channels.get(&chid).and_then(|channel| channel.get_member(uid))
+    }
+
+    pub fn member_modes(&self, chid: ChannelId, uid: UserId) -> Option<&BTreeSet<char>> {
+        self.membership(chid, uid).map(|member| member.get_modes())
+    }
+
+    /// Bans on a channel that match a user, including extbans.
+    /// Exceptions aren't taken into account.
+    pub fn bans_matching(&self, chid: ChannelId, uid: UserId) -> Vec<&ListEntry> {
+        self.bans_matching_at(chid, uid, 0)
+    }
+
+    fn bans_matching_at(&self, chid: ChannelId, uid: UserId, depth: u32) -> Vec<&ListEntry> {
+        let (channel, user) = match (self.channels.get(&chid), self.users.get(&uid)) {
+            (Some(channel), Some(user)) => (channel, user),
+            _ => return Vec::new(),
+        };
+        channel.bans().into_iter()
+            .filter(|ban| {
+                let ban = ExtBan::parse(&ban.mask, &self.isupport);
+                !ban.is_quiet() && extban::matches_at(&ban, user, self, depth)
+            })
+            .collect()
+    }
+
+    /// Quiets on a channel that match a user: entries of a `q` list mode
+    /// and quiet extbans in the ban list.
+    pub fn quiets_matching(&self, chid: ChannelId, uid: UserId) -> Vec<&ListEntry> {
+        let (channel, user) = match (self.channels.get(&chid), self.users.get(&uid)) {
+            (Some(channel), Some(user)) => (channel, user),
+            _ => return Vec::new(),
+        };
+        let mut matched: Vec<&ListEntry> = channel.bans().into_iter()
+            .filter(|ban| {
+                let ban = ExtBan::parse(&ban.mask, &self.isupport);
+                ban.is_quiet() && ban.matches(user, self)
+            })
+            .collect();
+        if self.isupport.mode_kind('q') == ModeKind::List {
+            matched.extend(channel.quiets().into_iter()
+                .filter(|quiet| ExtBan::parse(&quiet.mask, &self.isupport).matches(user, self)));
+        }
+        matched
+    }
+
+    /// Whether an entry of the list mode `mode`, such as `e` or `I`,
+    /// matches the user.  False if the server has no such list.
+    fn is_exempt(&self, channel: &Channel, user: &User, mode: char) -> bool {
+        if self.isupport.mode_kind(mode) != ModeKind::List {
+            return false;
+        }
+        channel.modes.list(mode).into_iter()
+            .any(|entry| ExtBan::parse(&entry.mask, &self.isupport).matches(user, self))
+    }
+
+    /// Predicts whether a message from `uid` to a channel would get
+    /// through.  Voice or any higher status overrides `+m`, bans and
+    /// quiets, as on most servers.
+    pub fn can_send(&self, uid: UserId, chid: ChannelId) -> Result<(), Denial> {
+        let channel = match self.channels.get(&chid) {
+            Some(channel) => channel,
+            None => return Err(Denial::UnknownChannel),
+        };
+        let user = match self.users.get(&uid) {
+            Some(user) => user,
+            None => return Err(Denial::UnknownUser),
+        };
+        let member = channel.get_member(uid);
+        if let Some(member) = member {
+            if !member.get_modes().is_empty() {
+                return Ok(());
+            }
+        }
+        if member.is_none() && channel.has_mode('n') {
+            return Err(Denial::NoExternalMessages);
+        }
+        if channel.has_mode('m') {
+            return Err(Denial::Moderated);
+        }
+        let exempt = self.is_exempt(channel, user, 'e');
+        let banned = self.bans_matching(chid, uid).into_iter()
+            .any(|ban| !ExtBan::parse(&ban.mask, &self.isupport).is_join_only());
+        if !exempt && banned {
+            return Err(Denial::Banned);
+        }
+        if !exempt && !self.quiets_matching(chid, uid).is_empty() {
+            return Err(Denial::Quieted);
+        }
+        Ok(())
+    }
+
+    /// Predicts whether we could join a channel again after leaving it,
+    /// e.g. before cycling it.  Only tracked channels can be evaluated.
+    pub fn can_join(&self, chid: ChannelId) -> Result<(), Denial> {
+        let channel = match self.channels.get(&chid) {
+            Some(channel) => channel,
+            None => return Err(Denial::UnknownChannel),
+        };
+        let user = match self.self_user() {
+            Some(user) => user,
+            None => return Err(Denial::UnknownUser),
+        };
+        if !self.is_exempt(channel, user, 'e') && !self.bans_matching(chid, self.self_id).is_empty() {
+            return Err(Denial::Banned);
+        }
+        if channel.has_mode('i') && !self.is_exempt(channel, user, 'I') {
+            return Err(Denial::InviteOnly);
+        }
+        if channel.key().is_some() {
+            return Err(Denial::KeyRequired);
+        }
+        if let Some(limit) = channel.limit() {
+            let mut others = channel.users.len();
+            if channel.users.contains_key(&self.self_id) {
+                others -= 1;
+            }
+            if others as u32 >= limit {
+                return Err(Denial::ChannelFull);
+            }
+        }
+        Ok(())
+    }
+
+    /// Every tracked user a mask such as `*!*@*.example.org` or an extban
+    /// such as `$a:account` would hit, e.g. to preview a ban.
+    pub fn users_matching_mask(&self, mask: &str) -> Vec<UserId> {
+        let ban = ExtBan::parse(mask, &self.isupport);
+        let mut matched: Vec<UserId> = self.users.values()
+            .filter(|user| ban.matches(user, self))
+            .map(|user| user.id)
+            .collect();
+        matched.sort();
+        matched
+    }
+
+    pub fn is_op(&self, chid: ChannelId, uid: UserId) -> bool {
+        self.membership(chid, uid).map(|m| m.has_mode('o')).unwrap_or(false)
+    }
+
+    pub fn is_voiced(&self, chid: ChannelId, uid: UserId) -> bool {
+        self.membership(chid, uid).map(|m| m.has_mode('v')).unwrap_or(false)
+    }
+
+    /// A counter bumped on every mutation; snapshots of the same `State`
+    /// with equal generations are identical.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn isupport(&self) -> &ISupport {
+        &self.isupport
+    }
+
+    /// Takes a snapshot of the state in constant time.  Users, channels
+    /// and server settings are shared with the snapshot, and later changes
+    /// copy only the entries they touch.  Replies still being collected,
+    /// such as a NAMES list, aren't part of the snapshot.
     pub fn clone_frozen(&self) -> FrozenState {
-        FrozenState(self.clone())
+        FrozenState(State {
+            user_seq: self.user_seq,
+            channel_seq: self.channel_seq,
+            lifecycle: self.lifecycle,
+            desired_channels: self.desired_channels.clone(),
+            self_nick: self.self_nick.clone(),
+            self_id: self.self_id,
+            pending_nick: self.pending_nick.clone(),
+            pending_login: None,
+            user_map: self.user_map.clone(),
+            users: self.users.clone(),
+            channel_map: self.channel_map.clone(),
+            channels: self.channels.clone(),
+            names_pending: HashMap::new(),
+            lists_pending: HashMap::new(),
+            metadata_pending: HashMap::new(),
+            pending_changes: Vec::new(),
+            isupport: self.isupport.clone(),
+            caps: self.caps.clone(),
+            message_time: None,
+            last_disconnect_reason: self.last_disconnect_reason.clone(),
+            whox_queries: VecDeque::new(),
+            generation: self.generation,
+        })
     }
 }
 
@@ -704,13 +2453,16 @@ impl State {
             Err(msg) => panic!("invalid state: {:?}, dump = {:?}", msg, self)
         };
     }
+}
 
+#[cfg(any(test, feature = "serde"))]
+impl State {
     fn validate_state_internal(&self) -> Result<(), String> {
         for (&id, state) in self.channels.iter() {
             if id != state.id {
                 return Err(format!("{:?} at channels[{:?}]", state.id, id));
             }
-            for &user_id in state.users.iter() {
+            for &user_id in state.users.keys() {
                 if let Some(user_state) = self.users.get(&user_id) {
                     if !user_state.channels.contains(&id) {
                         return Err(format!("{0:?} ref {1:?} => {1:?} ref {0:?} not holding", id, user_id));
@@ -726,7 +2478,7 @@ impl State {
             }
             for &chan_id in state.channels.iter() {
                 if let Some(chan_state) = self.channels.get(&chan_id) {
-                    if !chan_state.users.contains(&id) {
+                    if !chan_state.users.contains_key(&id) {
                         return Err(format!("{0:?} ref {1:?} => {1:?} ref {0:?} not holding", id, chan_id));
                     }
                 } else {
@@ -736,7 +2488,7 @@ impl State {
         }
         for (name, &id) in self.channel_map.iter() {
             if let Some(state) = self.channels.get(&id) {
-                if *name != IrcIdentifier::from_str(&state.name) {
+                if *name != self.identifier(&state.name) {
                     return Err(format!("{:?} at channel_map[{:?}]", state.id, name));
                 }
             } else {
@@ -745,7 +2497,7 @@ impl State {
         }
         for (name, &id) in self.user_map.iter() {
             if let Some(state) = self.users.get(&id) {
-                if *name != IrcIdentifier::from_str(state.get_nick()) {
+                if *name != self.identifier(state.get_nick()) {
                     return Err(format!("{:?} at user_map[{:?}]", state.id, name));
                 }
             } else {
@@ -785,9 +2537,238 @@ impl PartialEq for State {
         if self.self_nick != other.self_nick {
             return false;
         }
+        if self.lifecycle != other.lifecycle {
+            return false;
+        }
+        if self.desired_channels != other.desired_channels {
+            return false;
+        }
+        if self.isupport != other.isupport {
+            return false;
+        }
+        if self.caps != other.caps {
+            return false;
+        }
+        if self.last_disconnect_reason != other.last_disconnect_reason {
+            return false;
+        }
         if self.generation != other.generation {
             return false;
         }
         return true;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use irc::parse::IrcMsg;
+    use irc::{IrcEvent, JoinSuccess};
+
+    use super::{Denial, EventError, State, StateChange, StateError, UserId};
+
+    pub fn feed(state: &mut State, line: &str) -> Result<Vec<StateChange>, EventError> {
+        state.on_message(&IrcMsg::new(line.as_bytes().to_vec()).unwrap())
+    }
+
+    pub fn join(state: &mut State, channel: &str, nicks: &[&str]) -> Vec<StateChange> {
+        state.on_event(&IrcEvent::JoinBundle(Ok(JoinSuccess {
+            channel: channel.as_bytes().to_vec(),
+            nicks: nicks.iter().map(|nick| nick.to_string()).collect(),
+            topic: None,
+        }))).unwrap()
+    }
+
+    /// A registered state, as `me`, in one channel.
+    pub fn joined(channel: &str, nicks: &[&str]) -> State {
+        let mut state = State::new();
+        feed(&mut state, ":server 001 me :Welcome").unwrap();
+        join(&mut state, channel, nicks);
+        state
+    }
+
+    #[test]
+    fn test_part_unknown_channel() {
+        let mut state = joined("#chan", &["me", "alice"]);
+        let err = feed(&mut state, ":alice!a@host PART #elsewhere").unwrap_err();
+        assert_eq!(err.error, StateError::UnknownChannel("#elsewhere".to_string()));
+        assert!(err.changes.is_empty());
+        assert!(state.identify_nick("alice").is_some());
+    }
+
+    #[test]
+    fn test_part_not_member() {
+        let mut state = joined("#chan", &["me", "alice"]);
+        join(&mut state, "#other", &["me", "bob"]);
+        for line in [":bob!b@host PART #chan", ":alice!a@host KICK #chan bob :out"].iter() {
+            let err = feed(&mut state, line).unwrap_err();
+            match err.error {
+                StateError::Inconsistent(_) => (),
+                other => panic!("unexpected error {:?}", other),
+            }
+            assert!(err.changes.is_empty());
+        }
+        assert!(state.identify_nick("bob").is_some());
+    }
+
+    #[test]
+    fn test_self_kicked() {
+        let mut state = joined("#chan", &["me", "alice"]);
+        let chan_id = state.identify_channel("#chan").unwrap();
+        let changes = feed(&mut state, ":alice!a@host KICK #chan me :out").unwrap();
+        assert!(changes.contains(&StateChange::ChannelParted {
+            channel: chan_id,
+            name: "#chan".to_string(),
+        }));
+        assert_eq!(state.identify_channel("#chan"), None);
+        assert_eq!(state.identify_nick("alice"), None);
+        assert_eq!(state.desired_channels(), vec!["#chan"]);
+    }
+
+    #[test]
+    fn test_quit_unknown_user() {
+        let mut state = joined("#chan", &["me", "alice"]);
+        let err = feed(&mut state, ":ghost!g@host QUIT :bye").unwrap_err();
+        assert_eq!(err.error, StateError::UnknownUser("ghost".to_string()));
+        assert!(state.identify_nick("alice").is_some());
+    }
+
+    #[test]
+    fn test_nick_collision() {
+        let mut state = joined("#chan", &["me", "alice", "bob"]);
+        let alice = state.identify_nick("alice").unwrap();
+        let bob = state.identify_nick("bob").unwrap();
+        let err = feed(&mut state, ":alice!a@host NICK Bob").unwrap_err();
+        match err.error {
+            StateError::Inconsistent(_) => (),
+            other => panic!("unexpected error {:?}", other),
+        }
+        assert_eq!(state.identify_nick("alice"), Some(alice));
+        assert_eq!(state.identify_nick("bob"), Some(bob));
+    }
+
+    #[test]
+    fn test_casemapping_collision() {
+        let mut state = State::new();
+        feed(&mut state, ":server 001 me :Welcome").unwrap();
+        feed(&mut state, ":server 005 me CASEMAPPING=ascii :are supported").unwrap();
+        join(&mut state, "#chan", &["me", "a[", "a{"]);
+        join(&mut state, "#[x]", &["me"]);
+        let older = state.identify_nick("a[").unwrap();
+        let newer = state.identify_nick("a{").unwrap();
+        assert!(older < newer);
+        feed(&mut state, ":server 353 me = #[x] :b").unwrap();
+
+        let changes = feed(&mut state, ":server 005 me CASEMAPPING=rfc1459 :are supported").unwrap();
+        assert!(changes.contains(&StateChange::UserRemoved { user: newer, nick: "a{".to_string() }));
+        assert_eq!(state.identify_nick("a{"), Some(older));
+        assert_eq!(state.validate_state_internal(), Ok(()));
+
+        // NAMES buffered under the old casemapping still completes.
+        feed(&mut state, ":server 366 me #{x} :End of /NAMES list.").unwrap();
+        let chan_id = state.identify_channel("#[x]").unwrap();
+        let b = state.identify_nick("b").unwrap();
+        assert!(state.membership(chan_id, b).is_some());
+    }
+
+    #[test]
+    fn test_clone_frozen_shares() {
+        let mut state = joined("#chan", &["me", "alice"]);
+        feed(&mut state, ":server 353 me = #chan :alice bob").unwrap();
+        let frozen = state.clone_frozen();
+        assert!(Arc::ptr_eq(&state.isupport, &frozen.isupport));
+        assert!(Arc::ptr_eq(&state.desired_channels, &frozen.desired_channels));
+        assert!(frozen.names_pending.is_empty());
+        assert!(*frozen == state);
+    }
+
+    #[test]
+    fn test_sasl_before_welcome() {
+        let mut state = State::new();
+        feed(&mut state, ":server CAP * ACK :sasl").unwrap();
+        feed(&mut state, ":server 900 * me!user@host acct :You are now logged in as acct").unwrap();
+        assert!(state.self_user().is_none());
+        let changes = feed(&mut state, ":server 001 me :Welcome").unwrap();
+        let self_id = state.identify_nick("me").unwrap();
+        assert!(changes.contains(&StateChange::AccountChanged(self_id)));
+        let user = state.self_user().unwrap();
+        assert_eq!(user.account(), Some("acct"));
+        assert_eq!(user.get_prefix(), "me!user@host");
+    }
+
+    fn moderated_state() -> State {
+        let mut state = joined("#chan", &["me", "@op!o@op.host", "+voiced!v@v.host", "alice!a@a.host"]);
+        feed(&mut state, ":server 005 me CHANMODES=beIq,k,l,imnt PREFIX=(ov)@+ EXTBAN=~,j :are supported").unwrap();
+        join(&mut state, "#other", &["me", "bob!b@b.host"]);
+        state
+    }
+
+    fn set_mode(state: &mut State, modes: &str) {
+        feed(state, &format!(":op!o@op.host MODE #chan {}", modes)).unwrap();
+    }
+
+    #[test]
+    fn test_can_send() {
+        let mut state = moderated_state();
+        let chan_id = state.identify_channel("#chan").unwrap();
+        let op = state.identify_nick("op").unwrap();
+        let voiced = state.identify_nick("voiced").unwrap();
+        let alice = state.identify_nick("alice").unwrap();
+        let bob = state.identify_nick("bob").unwrap();
+        assert_eq!(state.can_send(alice, chan_id), Ok(()));
+        assert_eq!(state.can_send(bob, chan_id), Ok(()));
+        assert_eq!(state.can_send(UserId(999), chan_id), Err(Denial::UnknownUser));
+
+        set_mode(&mut state, "+n");
+        assert_eq!(state.can_send(bob, chan_id), Err(Denial::NoExternalMessages));
+        assert_eq!(state.can_send(alice, chan_id), Ok(()));
+
+        set_mode(&mut state, "+m");
+        assert_eq!(state.can_send(alice, chan_id), Err(Denial::Moderated));
+        assert_eq!(state.can_send(voiced, chan_id), Ok(()));
+        assert_eq!(state.can_send(op, chan_id), Ok(()));
+
+        // Status overrides bans; exceptions override bans and quiets.
+        set_mode(&mut state, "-m+bb *!*@a.host *!*@v.host");
+        assert_eq!(state.can_send(alice, chan_id), Err(Denial::Banned));
+        assert_eq!(state.can_send(voiced, chan_id), Ok(()));
+        set_mode(&mut state, "+e alice!*@*");
+        assert_eq!(state.can_send(alice, chan_id), Ok(()));
+        set_mode(&mut state, "-b+q *!*@a.host alice!*@*");
+        assert_eq!(state.can_send(alice, chan_id), Ok(()));
+        set_mode(&mut state, "-e alice!*@*");
+        assert_eq!(state.can_send(alice, chan_id), Err(Denial::Quieted));
+
+        // A join-only ban doesn't stop members talking.
+        set_mode(&mut state, "-q+b alice!*@* ~j:alice!*@*");
+        assert_eq!(state.can_send(alice, chan_id), Ok(()));
+    }
+
+    #[test]
+    fn test_can_join() {
+        let mut state = moderated_state();
+        let chan_id = state.identify_channel("#chan").unwrap();
+        assert_eq!(state.can_join(chan_id), Ok(()));
+
+        set_mode(&mut state, "+i");
+        assert_eq!(state.can_join(chan_id), Err(Denial::InviteOnly));
+        set_mode(&mut state, "+I me!*@*");
+        assert_eq!(state.can_join(chan_id), Ok(()));
+
+        set_mode(&mut state, "+k secret");
+        assert_eq!(state.can_join(chan_id), Err(Denial::KeyRequired));
+        set_mode(&mut state, "-k secret");
+
+        // We don't count against the limit ourselves.
+        set_mode(&mut state, "+l 3");
+        assert_eq!(state.can_join(chan_id), Err(Denial::ChannelFull));
+        set_mode(&mut state, "+l 4");
+        assert_eq!(state.can_join(chan_id), Ok(()));
+
+        set_mode(&mut state, "+b ~j:me!*@*");
+        assert_eq!(state.can_join(chan_id), Err(Denial::Banned));
+        set_mode(&mut state, "+e me!*@*");
+        assert_eq!(state.can_join(chan_id), Ok(()));
+    }
+}
@@ -0,0 +1,43 @@
+/// Where the connection a `State` is tracking is in its life.
+///
+/// A `State` starts out `Unregistered`, and the first message seen
+/// after `State::on_disconnect` starts a new connection, so one `State`
+/// can be carried across reconnects.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum Lifecycle {
+    /// Connected, waiting for RPL_WELCOME (001).
+    Unregistered,
+    /// The server has answered a CAP request; registration is held
+    /// until the client sends `CAP END`.
+    CapNegotiation,
+    /// RPL_WELCOME (001) has been received.
+    Registered,
+    /// The connection is gone.  Channels and users have been dropped;
+    /// server parameters and desired channels are kept.
+    Disconnected,
+}
+
+impl Lifecycle {
+    pub fn is_registered(&self) -> bool {
+        *self == Lifecycle::Registered
+    }
+}
+
+/// Why the last connection ended.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The server echoed our own QUIT.
+    Quit(String),
+    /// We were KILLed by an operator or the server.
+    Killed {
+        by: String,
+        reason: String,
+    },
+    /// The server closed the link with `ERROR`.
+    Error(String),
+    /// `State::on_disconnect` was called without any of the above, e.g.
+    /// because the socket closed.
+    ConnectionLost,
+}
@@ -0,0 +1,132 @@
+use irc_identifier::CaseMapping;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Glob {
+    /// `*`: any run of characters, including none.
+    Many,
+    /// `?`: exactly one character.
+    One,
+    Char(char),
+}
+
+fn compile(mask: &str, casemapping: CaseMapping) -> Vec<Glob> {
+    let mut globs = Vec::with_capacity(mask.len());
+    let mut chars = mask.chars();
+    while let Some(ch) = chars.next() {
+        let literal = match ch {
+            '*' => { globs.push(Glob::Many); continue; },
+            '?' => { globs.push(Glob::One); continue; },
+            // A trailing backslash stands for itself.
+            '\\' => chars.next().unwrap_or('\\'),
+            ch => ch,
+        };
+        // Escapes are resolved before folding, since RFC 1459 folds `\`.
+        let folded = casemapping.to_lower(&literal.to_string());
+        globs.extend(folded.chars().map(Glob::Char));
+    }
+    globs
+}
+
+/// Whether `subject`, such as `nick!user@host`, matches an IRC wildcard
+/// mask under `casemapping`.  `*` matches any run of characters, `?`
+/// any single one, and `\` makes the next character literal.
+pub fn matches(mask: &str, subject: &str, casemapping: CaseMapping) -> bool {
+    let globs = compile(mask, casemapping);
+    let subject: Vec<char> = casemapping.to_lower(subject).chars().collect();
+
+    let (mut g, mut s) = (0, 0);
+    // Where to resume if the text after the last `*` stops matching.
+    let mut backtrack: Option<(usize, usize)> = None;
+    while s < subject.len() {
+        match globs.get(g) {
+            Some(&Glob::Many) => {
+                g += 1;
+                backtrack = Some((g, s));
+                continue;
+            },
+            Some(&Glob::One) => {
+                g += 1;
+                s += 1;
+                continue;
+            },
+            Some(&Glob::Char(ch)) if ch == subject[s] => {
+                g += 1;
+                s += 1;
+                continue;
+            },
+            _ => (),
+        }
+        match backtrack {
+            Some((star_g, star_s)) => {
+                g = star_g;
+                s = star_s + 1;
+                backtrack = Some((star_g, star_s + 1));
+            },
+            None => return false,
+        }
+    }
+    globs[g..].iter().all(|glob| *glob == Glob::Many)
+}
+
+/// Completes a partial mask the way servers do for bans: `nick` becomes
+/// `nick!*@*`, `user@host` becomes `*!user@host` and `nick!user`
+/// becomes `nick!user@*`.
+pub fn normalize(mask: &str) -> String {
+    match (mask.find('!'), mask.find('@')) {
+        (Some(_), Some(_)) => mask.to_string(),
+        (Some(_), None) => format!("{}@*", mask),
+        (None, Some(_)) => format!("*!{}", mask),
+        (None, None) => format!("{}!*@*", mask),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use irc_identifier::CaseMapping;
+
+    use super::{matches, normalize};
+
+    #[test]
+    fn test_star_backtracking() {
+        let mapping = CaseMapping::Ascii;
+        assert!(matches("a*b*c", "abc", mapping));
+        assert!(matches("a*b*c", "axxbyybc", mapping));
+        assert!(matches("a*b*c", "abcbc", mapping));
+        assert!(!matches("a*b*c", "axxbyy", mapping));
+        assert!(!matches("a*b*c", "xabc", mapping));
+        assert!(matches("*", "", mapping));
+    }
+
+    #[test]
+    fn test_trailing_question_mark() {
+        let mapping = CaseMapping::Ascii;
+        assert!(matches("nick?", "nicks", mapping));
+        assert!(!matches("nick?", "nick", mapping));
+        assert!(!matches("nick?", "nickss", mapping));
+    }
+
+    #[test]
+    fn test_escaped_wildcards() {
+        let mapping = CaseMapping::Ascii;
+        assert!(matches("a\\*b", "a*b", mapping));
+        assert!(!matches("a\\*b", "axb", mapping));
+        assert!(matches("a\\?b", "a?b", mapping));
+        assert!(!matches("a\\?b", "axb", mapping));
+        assert!(matches("a\\\\b", "a\\b", mapping));
+    }
+
+    #[test]
+    fn test_casemapping() {
+        assert!(matches("Nick[a]!*@*", "nick{A}!user@host", CaseMapping::Rfc1459));
+        assert!(!matches("Nick[a]!*@*", "nick{A}!user@host", CaseMapping::Ascii));
+        assert!(matches("Nick[a]!*@*", "NICK[A]!user@host", CaseMapping::Ascii));
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("nick"), "nick!*@*");
+        assert_eq!(normalize("user@host"), "*!user@host");
+        assert_eq!(normalize("nick!user"), "nick!user@*");
+        assert_eq!(normalize("nick!user@host"), "nick!user@host");
+    }
+}
@@ -0,0 +1,361 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+/// How a channel mode letter consumes parameters, following the
+/// CHANMODES/PREFIX classification of ISUPPORT.
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum ModeKind {
+    /// Type A: list modes, always take a parameter (`b`, `e`, `I`)
+    List,
+    /// Type B: always take a parameter (`k`)
+    AlwaysArg,
+    /// Type C: take a parameter only when being set (`l`)
+    SetArg,
+    /// Type D: never take a parameter (`n`, `t`, `m`, ...)
+    NoArg,
+    /// Member status modes (`o`, `v`)
+    Prefix,
+}
+
+impl ModeKind {
+    fn takes_arg(&self, set: bool) -> bool {
+        match *self {
+            ModeKind::List => true,
+            ModeKind::AlwaysArg => true,
+            ModeKind::SetArg => set,
+            ModeKind::NoArg => false,
+            ModeKind::Prefix => true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ModeChange {
+    pub set: bool,
+    pub mode: char,
+    pub kind: ModeKind,
+    pub arg: Option<String>,
+}
+
+/// Splits a mode string such as `+nk-l` and its trailing parameters
+/// into individual changes.  Modes missing a required parameter are
+/// dropped.
+pub fn parse_mode_changes<F>(modestr: &str, args: &[String], classify: F) -> Vec<ModeChange>
+    where
+        F: Fn(char) -> ModeKind {
+
+    let mut changes = Vec::new();
+    let mut args = args.iter();
+    let mut set = true;
+    for mode in modestr.chars() {
+        match mode {
+            '+' => set = true,
+            '-' => set = false,
+            _ => {
+                let kind = classify(mode);
+                let arg = if kind.takes_arg(set) {
+                    match args.next() {
+                        Some(arg) => Some(arg.clone()),
+                        None => {
+                            warn!("Mode {:?} missing parameter in {:?}", mode, modestr);
+                            continue;
+                        }
+                    }
+                } else {
+                    None
+                };
+                changes.push(ModeChange {
+                    set: set,
+                    mode: mode,
+                    kind: kind,
+                    arg: arg,
+                });
+            }
+        }
+    }
+    changes
+}
+
+/// An entry of a list mode such as a ban, with who set it and when if
+/// the server told us.
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ListEntry {
+    pub mask: String,
+    pub set_by: Option<String>,
+    /// Unix time in seconds.
+    pub set_at: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ChannelModes {
+    flags: BTreeSet<char>,
+    params: BTreeMap<char, String>,
+    // List entries by mode, then by mask.
+    lists: BTreeMap<char, BTreeMap<String, ListEntry>>,
+}
+
+impl ChannelModes {
+    pub fn has_mode(&self, mode: char) -> bool {
+        self.flags.contains(&mode) || self.params.contains_key(&mode)
+    }
+
+    pub fn get_param(&self, mode: char) -> Option<&str> {
+        self.params.get(&mode).map(|v| &v[..])
+    }
+
+    pub fn key(&self) -> Option<&str> {
+        self.get_param('k')
+    }
+
+    pub fn limit(&self) -> Option<u32> {
+        self.get_param('l').and_then(|v| v.parse().ok())
+    }
+
+    /// The entries of a list mode such as `b`, ordered by mask.
+    pub fn list(&self, mode: char) -> Vec<&ListEntry> {
+        match self.lists.get(&mode) {
+            Some(list) => list.values().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Applies one change, returning whether anything was modified.
+    /// Prefix modes are not channel state and are ignored here.
+    pub fn apply(&mut self, change: &ModeChange) -> bool {
+        self.apply_from(change, None, None)
+    }
+
+    /// Like `apply`, recording who made the change and when on any list
+    /// entry it adds.
+    pub fn apply_from(&mut self, change: &ModeChange,
+                      set_by: Option<&str>, set_at: Option<u64>) -> bool {
+        match (change.kind, change.set, &change.arg) {
+            (ModeKind::Prefix, _, _) => false,
+            (ModeKind::List, true, &Some(ref mask)) => {
+                let list = self.lists.entry(change.mode).or_insert_with(BTreeMap::new);
+                if list.contains_key(mask) {
+                    return false;
+                }
+                list.insert(mask.clone(), ListEntry {
+                    mask: mask.clone(),
+                    set_by: set_by.map(|set_by| set_by.to_string()),
+                    set_at: set_at,
+                });
+                true
+            },
+            (ModeKind::List, false, &Some(ref mask)) => {
+                match self.lists.get_mut(&change.mode) {
+                    Some(list) => list.remove(mask).is_some(),
+                    None => false,
+                }
+            },
+            (_, true, &Some(ref arg)) => {
+                let prev = self.params.insert(change.mode, arg.clone());
+                prev.as_ref() != Some(arg)
+            },
+            (_, true, &None) => self.flags.insert(change.mode),
+            (_, false, _) => {
+                let had_param = self.params.remove(&change.mode).is_some();
+                self.flags.remove(&change.mode) || had_param
+            },
+        }
+    }
+
+    /// Replaces a whole list, as the replies to `MODE #chan +b` and the
+    /// like describe it.  Returns whether anything changed.
+    pub fn replace_list(&mut self, mode: char, entries: Vec<ListEntry>) -> bool {
+        let list: BTreeMap<String, ListEntry> = entries.into_iter()
+            .map(|entry| (entry.mask.clone(), entry))
+            .collect();
+        if self.lists.get(&mode) == Some(&list) {
+            return false;
+        }
+        if list.is_empty() && !self.lists.contains_key(&mode) {
+            return false;
+        }
+        self.lists.insert(mode, list);
+        true
+    }
+
+    /// Replaces every non-list mode with those set by `changes`, as an
+    /// RPL_CHANNELMODEIS reply describes the full set.  Returns the
+    /// changes that actually took effect.
+    pub fn replace_settings<F>(&mut self, changes: &[ModeChange], classify: F) -> Vec<ModeChange>
+        where
+            F: Fn(char) -> ModeKind {
+
+        let mut updated = ChannelModes {
+            flags: BTreeSet::new(),
+            params: BTreeMap::new(),
+            lists: self.lists.clone(),
+        };
+        for change in changes.iter().filter(|c| c.kind != ModeKind::List) {
+            updated.apply(change);
+        }
+
+        let mut effective = Vec::new();
+        for &mode in self.flags.difference(&updated.flags) {
+            effective.push(ModeChange {
+                set: false,
+                mode: mode,
+                kind: classify(mode),
+                arg: None,
+            });
+        }
+        for (&mode, value) in self.params.iter() {
+            if !updated.params.contains_key(&mode) {
+                let kind = classify(mode);
+                effective.push(ModeChange {
+                    set: false,
+                    mode: mode,
+                    kind: kind,
+                    arg: if kind == ModeKind::AlwaysArg { Some(value.clone()) } else { None },
+                });
+            }
+        }
+        for change in changes.iter().filter(|c| c.set && c.kind != ModeKind::List) {
+            let unchanged = match change.arg {
+                Some(ref arg) => self.params.get(&change.mode) == Some(arg),
+                None => self.flags.contains(&change.mode),
+            };
+            if !unchanged {
+                effective.push(change.clone());
+            }
+        }
+
+        *self = updated;
+        effective
+    }
+}
+
+/// A user's presence in a channel.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Membership {
+    modes: BTreeSet<char>,
+}
+
+impl Membership {
+    pub fn with_modes(modes: BTreeSet<char>) -> Membership {
+        Membership { modes: modes }
+    }
+
+    pub fn get_modes(&self) -> &BTreeSet<char> {
+        &self.modes
+    }
+
+    pub fn has_mode(&self, mode: char) -> bool {
+        self.modes.contains(&mode)
+    }
+
+    pub fn set_modes(&mut self, modes: BTreeSet<char>) -> bool {
+        let changed = self.modes != modes;
+        self.modes = modes;
+        changed
+    }
+
+    /// Applies a status (prefix) mode change, returning whether anything
+    /// was modified.
+    pub fn apply(&mut self, change: &ModeChange) -> bool {
+        if change.set {
+            self.modes.insert(change.mode)
+        } else {
+            self.modes.remove(&change.mode)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_mode_changes, ChannelModes, ModeChange, ModeKind};
+
+    fn classify(mode: char) -> ModeKind {
+        match mode {
+            'b' | 'e' | 'I' => ModeKind::List,
+            'k' => ModeKind::AlwaysArg,
+            'l' => ModeKind::SetArg,
+            'o' | 'v' => ModeKind::Prefix,
+            _ => ModeKind::NoArg,
+        }
+    }
+
+    fn parse(modestr: &str, args: &[&str]) -> Vec<ModeChange> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        parse_mode_changes(modestr, &args, classify)
+    }
+
+    fn change(set: bool, mode: char, arg: Option<&str>) -> ModeChange {
+        ModeChange {
+            set: set,
+            mode: mode,
+            kind: classify(mode),
+            arg: arg.map(|arg| arg.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_parse_arguments() {
+        assert_eq!(parse("+nk-l", &["secret"]), vec![
+            change(true, 'n', None),
+            change(true, 'k', Some("secret")),
+            change(false, 'l', None),
+        ]);
+        assert_eq!(parse("-k+l", &["secret", "10"]), vec![
+            change(false, 'k', Some("secret")),
+            change(true, 'l', Some("10")),
+        ]);
+        assert_eq!(parse("+ov-b", &["a", "b", "*!*@host"]), vec![
+            change(true, 'o', Some("a")),
+            change(true, 'v', Some("b")),
+            change(false, 'b', Some("*!*@host")),
+        ]);
+    }
+
+    #[test]
+    fn test_parse_missing_parameter() {
+        assert_eq!(parse("+kl", &["secret"]), vec![change(true, 'k', Some("secret"))]);
+        assert_eq!(parse("+bn", &[]), vec![change(true, 'n', None)]);
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut modes = ChannelModes::default();
+        assert!(modes.apply(&change(true, 'k', Some("secret"))));
+        assert!(!modes.apply(&change(true, 'k', Some("secret"))));
+        assert_eq!(modes.key(), Some("secret"));
+        assert!(modes.apply(&change(true, 'l', Some("10"))));
+        assert_eq!(modes.limit(), Some(10));
+        assert!(modes.apply(&change(false, 'l', None)));
+        assert!(!modes.has_mode('l'));
+        assert!(modes.apply(&change(false, 'k', Some("other"))));
+        assert_eq!(modes.key(), None);
+        assert!(!modes.apply(&change(true, 'o', Some("a"))));
+    }
+
+    #[test]
+    fn test_replace_settings() {
+        let mut modes = ChannelModes::default();
+        for change in parse("+ntk+l+b", &["secret", "10", "*!*@host"]).iter() {
+            modes.apply(change);
+        }
+
+        let effective = modes.replace_settings(&parse("+nm+l", &["10"]), classify);
+        assert_eq!(effective, vec![
+            change(false, 't', None),
+            change(false, 'k', Some("secret")),
+            change(true, 'm', None),
+        ]);
+        assert!(modes.has_mode('n') && modes.has_mode('m') && !modes.has_mode('t'));
+        assert_eq!(modes.key(), None);
+        assert_eq!(modes.limit(), Some(10));
+        assert_eq!(modes.list('b').len(), 1);
+
+        let effective = modes.replace_settings(&parse("+nl", &["20"]), classify);
+        assert_eq!(effective, vec![
+            change(false, 'm', None),
+            change(true, 'l', Some("20")),
+        ]);
+        assert_eq!(modes.replace_settings(&parse("+nl", &["20"]), classify), vec![]);
+    }
+}
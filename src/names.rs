@@ -0,0 +1,51 @@
+use std::collections::BTreeSet;
+
+use isupport::ISupport;
+
+/// One entry of a RPL_NAMREPLY (353) list, such as `@+nick` or, with
+/// userhost-in-names, `@nick!user@host`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NamesEntry<'a> {
+    pub modes: BTreeSet<char>,
+    pub nick: &'a str,
+    /// The full `nick!user@host`, when the server sent one.
+    pub prefix: Option<&'a str>,
+}
+
+/// Parses a NAMES entry against the server's PREFIX token.  Without
+/// multi-prefix only the highest status symbol is sent, so at most one
+/// is stripped; without userhost-in-names the rest is taken as a nick.
+pub fn parse_entry<'a>(isupport: &ISupport, entry: &'a str,
+                       multi_prefix: bool, userhost_in_names: bool) -> NamesEntry<'a> {
+    let (modes, rest) = if multi_prefix {
+        isupport.split_status_prefixes(entry)
+    } else {
+        let mut modes = BTreeSet::new();
+        let mut rest = entry;
+        if let Some(ch) = entry.chars().next() {
+            if let Some(mode) = isupport.prefix_mode(ch) {
+                modes.insert(mode);
+                rest = &entry[ch.len_utf8()..];
+            }
+        }
+        (modes, rest)
+    };
+
+    let nick_end = if userhost_in_names {
+        rest.find(|ch| ch == '!' || ch == '@')
+    } else {
+        None
+    };
+    match nick_end {
+        Some(idx) => NamesEntry {
+            modes: modes,
+            nick: &rest[..idx],
+            prefix: Some(rest),
+        },
+        None => NamesEntry {
+            modes: modes,
+            nick: rest,
+            prefix: None,
+        },
+    }
+}
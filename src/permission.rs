@@ -0,0 +1,43 @@
+use std::fmt;
+
+/// Why the server would refuse a message or a join, as predicted from
+/// the channel state we track.
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum Denial {
+    /// We aren't tracking the channel, so can't say.
+    UnknownChannel,
+    /// We aren't tracking the user, or, for our own joins, haven't
+    /// registered yet.
+    UnknownUser,
+    /// `+n` and the sender isn't a member.
+    NoExternalMessages,
+    /// `+m` and the sender has no voice or higher status.
+    Moderated,
+    /// A ban matches and no exception does.
+    Banned,
+    /// A quiet matches and no exception does.
+    Quieted,
+    /// `+i` and no invite exception matches.
+    InviteOnly,
+    /// `+k`; the join must give the key.
+    KeyRequired,
+    /// `+l` and the channel is at its limit.
+    ChannelFull,
+}
+
+impl fmt::Display for Denial {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match *self {
+            Denial::UnknownChannel => "not tracking that channel",
+            Denial::UnknownUser => "not tracking that user",
+            Denial::NoExternalMessages => "no external messages (+n)",
+            Denial::Moderated => "channel is moderated (+m)",
+            Denial::Banned => "banned",
+            Denial::Quieted => "quieted",
+            Denial::InviteOnly => "channel is invite only (+i)",
+            Denial::KeyRequired => "channel requires a key (+k)",
+            Denial::ChannelFull => "channel is full (+l)",
+        };
+        f.write_str(reason)
+    }
+}
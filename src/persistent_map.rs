@@ -0,0 +1,413 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::iter::FromIterator;
+use std::ops::Index;
+use std::slice;
+use std::sync::Arc;
+
+/// Hash bits consumed per level of the trie.
+const BITS: u32 = 5;
+
+/// A hash map whose clones share structure: a hash array mapped trie of
+/// reference-counted nodes.  Cloning is O(1), and changing an entry
+/// copies only the nodes on its path, so snapshots stay cheap to take
+/// and to diverge from.
+#[derive(Clone)]
+pub struct PersistentMap<K, V> {
+    root: Arc<Node<K, V>>,
+    len: usize,
+}
+
+#[derive(Clone)]
+enum Node<K, V> {
+    /// Children for the hash chunks whose bits are set, in bit order.
+    Branch(u32, Vec<Arc<Node<K, V>>>),
+    /// Entries whose keys share the whole hash.
+    Leaf(u64, Vec<(K, V)>),
+}
+
+fn hash_key<K: Hash>(key: &K) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The bit for `hash` in a branch at `shift`, and the index of its
+/// child given the branch's bitmap.
+fn slot(bitmap: u32, hash: u64, shift: u32) -> (u32, usize) {
+    let bit = 1 << ((hash >> shift) & 0x1f);
+    (bit, (bitmap & (bit - 1)).count_ones() as usize)
+}
+
+impl<K: Eq, V> Node<K, V> {
+    fn get(&self, hash: u64, shift: u32, key: &K) -> Option<&V> {
+        match *self {
+            Node::Branch(bitmap, ref children) => {
+                let (bit, idx) = slot(bitmap, hash, shift);
+                if bitmap & bit == 0 {
+                    return None;
+                }
+                children[idx].get(hash, shift + BITS, key)
+            },
+            Node::Leaf(leaf_hash, ref entries) => {
+                if leaf_hash != hash {
+                    return None;
+                }
+                entries.iter().find(|entry| entry.0 == *key).map(|entry| &entry.1)
+            },
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match *self {
+            Node::Branch(_, ref children) => children.is_empty(),
+            Node::Leaf(_, ref entries) => entries.is_empty(),
+        }
+    }
+}
+
+impl<K: Eq + Clone, V: Clone> Node<K, V> {
+    fn get_mut(&mut self, hash: u64, shift: u32, key: &K) -> Option<&mut V> {
+        match *self {
+            Node::Branch(bitmap, ref mut children) => {
+                let (bit, idx) = slot(bitmap, hash, shift);
+                if bitmap & bit == 0 {
+                    return None;
+                }
+                Arc::make_mut(&mut children[idx]).get_mut(hash, shift + BITS, key)
+            },
+            Node::Leaf(leaf_hash, ref mut entries) => {
+                if leaf_hash != hash {
+                    return None;
+                }
+                entries.iter_mut().find(|entry| entry.0 == *key).map(|entry| &mut entry.1)
+            },
+        }
+    }
+
+    fn insert(&mut self, hash: u64, shift: u32, key: K, value: V) -> Option<V> {
+        match *self {
+            Node::Branch(ref mut bitmap, ref mut children) => {
+                let (bit, idx) = slot(*bitmap, hash, shift);
+                if *bitmap & bit == 0 {
+                    *bitmap |= bit;
+                    children.insert(idx, Arc::new(Node::Leaf(hash, vec![(key, value)])));
+                    return None;
+                }
+                // A leaf for another hash moves down into a new branch,
+                // where the two hashes may part.
+                let split = match *children[idx] {
+                    Node::Leaf(leaf_hash, _) if leaf_hash != hash => Some(leaf_hash),
+                    _ => None,
+                };
+                if let Some(leaf_hash) = split {
+                    let (leaf_bit, _) = slot(0, leaf_hash, shift + BITS);
+                    let leaf = children[idx].clone();
+                    children[idx] = Arc::new(Node::Branch(leaf_bit, vec![leaf]));
+                }
+                Arc::make_mut(&mut children[idx]).insert(hash, shift + BITS, key, value)
+            },
+            Node::Leaf(_, ref mut entries) => {
+                for entry in entries.iter_mut() {
+                    if entry.0 == key {
+                        return Some(::std::mem::replace(&mut entry.1, value));
+                    }
+                }
+                entries.push((key, value));
+                None
+            },
+        }
+    }
+
+    fn remove(&mut self, hash: u64, shift: u32, key: &K) -> Option<V> {
+        match *self {
+            Node::Branch(ref mut bitmap, ref mut children) => {
+                let (bit, idx) = slot(*bitmap, hash, shift);
+                if *bitmap & bit == 0 {
+                    return None;
+                }
+                let removed = Arc::make_mut(&mut children[idx]).remove(hash, shift + BITS, key);
+                if children[idx].is_empty() {
+                    *bitmap &= !bit;
+                    children.remove(idx);
+                }
+                removed
+            },
+            Node::Leaf(leaf_hash, ref mut entries) => {
+                if leaf_hash != hash {
+                    return None;
+                }
+                entries.iter().position(|entry| entry.0 == *key)
+                    .map(|pos| entries.swap_remove(pos).1)
+            },
+        }
+    }
+}
+
+impl<K, V> PersistentMap<K, V> {
+    pub fn new() -> PersistentMap<K, V> {
+        PersistentMap {
+            root: Arc::new(Node::Branch(0, Vec::new())),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn iter<'a>(&'a self) -> Iter<'a, K, V> {
+        let mut iter = Iter {
+            branches: Vec::new(),
+            entries: [].iter(),
+            remaining: self.len,
+        };
+        iter.descend(&self.root);
+        iter
+    }
+
+    pub fn keys<'a>(&'a self) -> Keys<'a, K, V> {
+        Keys(self.iter())
+    }
+
+    pub fn values<'a>(&'a self) -> Values<'a, K, V> {
+        Values(self.iter())
+    }
+}
+
+impl<K: Hash + Eq, V> PersistentMap<K, V> {
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.root.get(hash_key(key), 0, key)
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> PersistentMap<K, V> {
+    /// Copies the nodes on the entry's path that are shared with other
+    /// maps; nothing is copied if the key is absent.
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        if !self.contains_key(key) {
+            return None;
+        }
+        Arc::make_mut(&mut self.root).get_mut(hash_key(key), 0, key)
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let hash = hash_key(&key);
+        let previous = Arc::make_mut(&mut self.root).insert(hash, 0, key, value);
+        if previous.is_none() {
+            self.len += 1;
+        }
+        previous
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        if !self.contains_key(key) {
+            return None;
+        }
+        let removed = Arc::make_mut(&mut self.root).remove(hash_key(key), 0, key);
+        if removed.is_some() {
+            self.len -= 1;
+        }
+        removed
+    }
+}
+
+pub struct Iter<'a, K: 'a, V: 'a> {
+    branches: Vec<slice::Iter<'a, Arc<Node<K, V>>>>,
+    entries: slice::Iter<'a, (K, V)>,
+    remaining: usize,
+}
+
+impl<'a, K, V> Iter<'a, K, V> {
+    fn descend(&mut self, node: &'a Node<K, V>) {
+        match *node {
+            Node::Branch(_, ref children) => self.branches.push(children.iter()),
+            Node::Leaf(_, ref entries) => self.entries = entries.iter(),
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        loop {
+            if let Some(&(ref key, ref value)) = self.entries.next() {
+                self.remaining -= 1;
+                return Some((key, value));
+            }
+            let next = match self.branches.last_mut() {
+                Some(children) => children.next(),
+                None => return None,
+            };
+            match next {
+                Some(node) => self.descend(node),
+                None => { self.branches.pop(); },
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+pub struct Keys<'a, K: 'a, V: 'a>(Iter<'a, K, V>);
+
+impl<'a, K, V> Iterator for Keys<'a, K, V> {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<&'a K> {
+        self.0.next().map(|(key, _)| key)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+pub struct Values<'a, K: 'a, V: 'a>(Iter<'a, K, V>);
+
+impl<'a, K, V> Iterator for Values<'a, K, V> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<&'a V> {
+        self.0.next().map(|(_, value)| value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl<'a, K, V> IntoIterator for &'a PersistentMap<K, V> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.iter()
+    }
+}
+
+impl<K, V> Default for PersistentMap<K, V> {
+    fn default() -> PersistentMap<K, V> {
+        PersistentMap::new()
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> FromIterator<(K, V)> for PersistentMap<K, V> {
+    fn from_iter<I: IntoIterator<Item=(K, V)>>(iter: I) -> PersistentMap<K, V> {
+        let mut map = PersistentMap::new();
+        for (key, value) in iter {
+            map.insert(key, value);
+        }
+        map
+    }
+}
+
+impl<'a, K: Hash + Eq, V> Index<&'a K> for PersistentMap<K, V> {
+    type Output = V;
+
+    fn index(&self, key: &K) -> &V {
+        self.get(key).expect("no entry found for key")
+    }
+}
+
+impl<K: Hash + Eq, V: PartialEq> PartialEq for PersistentMap<K, V> {
+    fn eq(&self, other: &PersistentMap<K, V>) -> bool {
+        if Arc::ptr_eq(&self.root, &other.root) {
+            return true;
+        }
+        self.len == other.len
+            && self.iter().all(|(key, value)| other.get(key) == Some(value))
+    }
+}
+
+impl<K: Hash + Eq, V: Eq> Eq for PersistentMap<K, V> {}
+
+impl<K: fmt::Debug, V: fmt::Debug> fmt::Debug for PersistentMap<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<K, V> ::serde::Serialize for PersistentMap<K, V>
+    where K: ::serde::Serialize, V: ::serde::Serialize
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: ::serde::Serializer {
+        serializer.collect_map(self.iter())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, K, V> ::serde::Deserialize<'de> for PersistentMap<K, V>
+    where K: ::serde::Deserialize<'de> + Hash + Eq + Clone, V: ::serde::Deserialize<'de> + Clone
+{
+    fn deserialize<D>(deserializer: D) -> Result<PersistentMap<K, V>, D::Error>
+        where D: ::serde::Deserializer<'de>
+    {
+        let entries: ::std::collections::HashMap<K, V> =
+            try!(::serde::Deserialize::deserialize(deserializer));
+        Ok(entries.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::PersistentMap;
+
+    #[test]
+    fn test_matches_hashmap() {
+        let mut map = PersistentMap::new();
+        let mut expected = HashMap::new();
+        for i in 0..2000u64 {
+            let key = i.wrapping_mul(2654435761) % 1500;
+            if i % 3 == 0 {
+                assert_eq!(map.remove(&key), expected.remove(&key));
+            } else {
+                assert_eq!(map.insert(key, i), expected.insert(key, i));
+            }
+            assert_eq!(map.len(), expected.len());
+        }
+        assert_eq!(map.iter().count(), expected.len());
+        for (key, value) in expected.iter() {
+            assert_eq!(map.get(key), Some(value));
+        }
+        for (key, value) in map.iter() {
+            assert_eq!(expected.get(key), Some(value));
+        }
+    }
+
+    #[test]
+    fn test_clones_diverge() {
+        let original: PersistentMap<u32, u32> = (0..100).map(|i| (i, i)).collect();
+        let mut changed = original.clone();
+        *changed.get_mut(&7).unwrap() = 700;
+        changed.remove(&8);
+        changed.insert(100, 100);
+        assert_eq!(original[&7], 7);
+        assert_eq!(original.get(&8), Some(&8));
+        assert_eq!(original.get(&100), None);
+        assert_eq!(original.len(), 100);
+        assert_eq!(changed[&7], 700);
+        assert_eq!(changed.get(&8), None);
+        assert_eq!(changed.len(), 100);
+        assert!(original != changed);
+        changed.insert(7, 7);
+        changed.insert(8, 8);
+        changed.remove(&100);
+        assert!(original == changed);
+    }
+}
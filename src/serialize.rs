@@ -0,0 +1,158 @@
+//! Serde support, enabled by the `serde` feature.
+//!
+//! `State` is written as a versioned document holding its users and
+//! channels; the nick and channel indexes are rebuilt when reading it
+//! back, and documents that don't describe a consistent state are
+//! rejected.
+
+use std::sync::Arc;
+
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use persistent_map::PersistentMap;
+use {Capabilities, Channel, DisconnectReason, FrozenState, ISupport, Lifecycle, State, User, UserId};
+
+/// Bumped whenever the serialized layout of `State` changes.
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Serialize)]
+struct StateSchemaRef<'a> {
+    version: u32,
+    user_seq: u64,
+    channel_seq: u64,
+    generation: u64,
+    lifecycle: Lifecycle,
+    last_disconnect_reason: Option<&'a DisconnectReason>,
+    desired_channels: Vec<&'a str>,
+    self_nick: &'a str,
+    self_id: UserId,
+    isupport: &'a ISupport,
+    caps: &'a Capabilities,
+    users: Vec<&'a User>,
+    channels: Vec<&'a Channel>,
+}
+
+#[derive(Deserialize)]
+struct StateSchema {
+    version: u32,
+    user_seq: u64,
+    channel_seq: u64,
+    generation: u64,
+    lifecycle: Lifecycle,
+    last_disconnect_reason: Option<DisconnectReason>,
+    desired_channels: Vec<String>,
+    self_nick: String,
+    self_id: UserId,
+    isupport: ISupport,
+    caps: Capabilities,
+    users: Vec<User>,
+    channels: Vec<Channel>,
+}
+
+impl Serialize for State {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        let mut users: Vec<&User> = self.users.values().collect();
+        users.sort_by(|a, b| a.id.cmp(&b.id));
+        let mut channels: Vec<&Channel> = self.channels.values().collect();
+        channels.sort_by(|a, b| a.id.cmp(&b.id));
+
+        StateSchemaRef {
+            version: SCHEMA_VERSION,
+            user_seq: self.user_seq,
+            channel_seq: self.channel_seq,
+            generation: self.generation,
+            lifecycle: self.lifecycle,
+            last_disconnect_reason: self.last_disconnect_reason(),
+            desired_channels: self.desired_channels(),
+            self_nick: &self.self_nick,
+            self_id: self.self_id,
+            isupport: &self.isupport,
+            caps: &self.caps,
+            users: users,
+            channels: channels,
+        }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for State {
+    fn deserialize<D>(deserializer: D) -> Result<State, D::Error> where D: Deserializer<'de> {
+        let schema = try!(StateSchema::deserialize(deserializer));
+        if schema.version != SCHEMA_VERSION {
+            return Err(D::Error::custom(format!(
+                "unsupported schema version {}, expected {}", schema.version, SCHEMA_VERSION)));
+        }
+
+        let mut state = State::new();
+        state.user_seq = schema.user_seq;
+        state.channel_seq = schema.channel_seq;
+        state.generation = schema.generation;
+        state.lifecycle = schema.lifecycle;
+        state.last_disconnect_reason = schema.last_disconnect_reason;
+        state.desired_channels = Arc::new(schema.desired_channels.into_iter().collect());
+        state.self_nick = schema.self_nick;
+        state.self_id = schema.self_id;
+        state.isupport = Arc::new(schema.isupport);
+        state.caps = Arc::new(schema.caps);
+
+        let mut user_map = PersistentMap::new();
+        let mut users = PersistentMap::new();
+        for user in schema.users.into_iter() {
+            if user_map.insert(state.identifier(user.get_nick()), user.id).is_some() {
+                return Err(D::Error::custom(format!("duplicate nick {:?}", user.get_nick())));
+            }
+            users.insert(user.id, user);
+        }
+        let mut channel_map = PersistentMap::new();
+        let mut channels = PersistentMap::new();
+        for channel in schema.channels.into_iter() {
+            if channel_map.insert(state.identifier(&channel.name), channel.id).is_some() {
+                return Err(D::Error::custom(format!("duplicate channel {:?}", channel.name)));
+            }
+            channels.insert(channel.id, channel);
+        }
+        state.user_map = user_map;
+        state.users = users;
+        state.channel_map = channel_map;
+        state.channels = channels;
+
+        match state.validate_state_internal() {
+            Ok(()) => Ok(state),
+            Err(msg) => Err(D::Error::custom(format!("inconsistent state: {}", msg))),
+        }
+    }
+}
+
+impl Serialize for FrozenState {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        let FrozenState(ref state) = *self;
+        state.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for FrozenState {
+    fn deserialize<D>(deserializer: D) -> Result<FrozenState, D::Error> where D: Deserializer<'de> {
+        State::deserialize(deserializer).map(FrozenState)
+    }
+}
+
+/// (De)serializes an `IrcMsgPrefix` as its string form.
+pub mod prefix {
+    use std::borrow::IntoCow;
+
+    use irc::parse::IrcMsgPrefix;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(prefix: &IrcMsgPrefix<'static>, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        serializer.serialize_str(prefix.as_slice())
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<IrcMsgPrefix<'static>, D::Error>
+        where D: Deserializer<'de>
+    {
+        let prefix = try!(String::deserialize(deserializer));
+        Ok(IrcMsgPrefix::new(prefix.into_cow()))
+    }
+}
@@ -0,0 +1,68 @@
+use std::collections::VecDeque;
+
+/// Previous topics kept per channel.
+pub const TOPIC_HISTORY_LEN: usize = 16;
+
+/// A channel topic and, when the server told us, who set it and when.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Topic {
+    pub text: String,
+    /// A nick or full prefix, as sent by the server.
+    pub set_by: Option<String>,
+    /// Unix time in seconds.
+    pub set_at: Option<u64>,
+}
+
+/// The current topic of a channel and the ones it replaced, oldest
+/// first.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct TopicHistory {
+    current: Topic,
+    previous: VecDeque<Topic>,
+}
+
+impl TopicHistory {
+    pub fn new(current: Topic) -> TopicHistory {
+        TopicHistory {
+            current: current,
+            previous: VecDeque::new(),
+        }
+    }
+
+    pub fn current(&self) -> &Topic {
+        &self.current
+    }
+
+    pub fn previous(&self) -> Vec<&Topic> {
+        self.previous.iter().collect()
+    }
+
+    /// Replaces the current topic, returning whether it changed.  The old
+    /// topic is kept unless it was unset or the text didn't change.
+    pub fn set(&mut self, topic: Topic) -> bool {
+        if self.current == topic {
+            return false;
+        }
+        if self.current.text != topic.text && self.current != Topic::default() {
+            if self.previous.len() == TOPIC_HISTORY_LEN {
+                self.previous.pop_front();
+            }
+            self.previous.push_back(self.current.clone());
+        }
+        self.current = topic;
+        true
+    }
+
+    /// Fills in the setter and time of the current topic (RPL_TOPICWHOTIME).
+    pub fn set_metadata(&mut self, set_by: &str, set_at: Option<u64>) -> bool {
+        let set_by = Some(set_by.to_string());
+        if self.current.set_by == set_by && self.current.set_at == set_at {
+            return false;
+        }
+        self.current.set_by = set_by;
+        self.current.set_at = set_at;
+        true
+    }
+}
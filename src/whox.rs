@@ -0,0 +1,170 @@
+/// Field letters of a WHOX reply, in the order servers send them
+/// whatever order they were requested in.
+const FIELD_ORDER: &'static str = "tcuihsnfdlaor";
+
+/// The largest token servers accept.
+const MAX_TOKEN: u16 = 999;
+
+/// A WHOX request: the fields to ask for and the token that marks the
+/// replies (354) as ours.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WhoxQuery {
+    fields: String,
+    token: u16,
+}
+
+impl WhoxQuery {
+    /// Builds a query for `fields`, e.g. `cuhnfar`.  The token field is
+    /// always requested, and unknown field letters are dropped.  `None`
+    /// if the token is over 999, which servers don't accept.
+    pub fn new(fields: &str, token: u16) -> Option<WhoxQuery> {
+        if token > MAX_TOKEN {
+            return None;
+        }
+        Some(WhoxQuery {
+            fields: FIELD_ORDER.chars()
+                .filter(|&ch| ch == 't' || fields.contains(ch))
+                .collect(),
+            token: token,
+        })
+    }
+
+    pub fn fields(&self) -> &str {
+        &self.fields
+    }
+
+    pub fn token(&self) -> u16 {
+        self.token
+    }
+
+    /// The command to send, e.g. `WHO #chan %tcuhnfar,42`.
+    pub fn command(&self, mask: &str) -> String {
+        format!("WHO {} %{},{}", mask, self.fields, self.token)
+    }
+
+    /// Splits the arguments of a 354 reply, after our own nick, into
+    /// fields.  `None` if the reply doesn't belong to this query.
+    pub fn parse_reply<'a>(&self, args: &'a [String]) -> Option<WhoxReply<'a>> {
+        if args.len() != self.fields.len() || args[0] != self.token.to_string() {
+            return None;
+        }
+        let mut reply = WhoxReply::default();
+        for (field, value) in self.fields.chars().zip(args.iter()) {
+            let value = Some(&value[..]);
+            match field {
+                'c' if value != Some("*") => reply.channel = value,
+                'u' => reply.username = value,
+                'i' if value != Some("255.255.255.255") => reply.ip = value,
+                'h' => reply.hostname = value,
+                's' => reply.server = value,
+                'n' => reply.nick = value,
+                'f' => reply.flags = value,
+                'd' => reply.hops = value,
+                'l' => reply.idle = value,
+                'a' => reply.account = value,
+                'o' => reply.oplevel = value,
+                'r' => reply.realname = value,
+                _ => (),
+            }
+        }
+        Some(reply)
+    }
+}
+
+/// The requested fields of a single WHOX reply.  `channel` is `None`
+/// for `*`, and `ip` for servers that hide it; `account` is `0` for
+/// users who aren't logged in.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct WhoxReply<'a> {
+    pub channel: Option<&'a str>,
+    pub username: Option<&'a str>,
+    pub ip: Option<&'a str>,
+    pub hostname: Option<&'a str>,
+    pub server: Option<&'a str>,
+    pub nick: Option<&'a str>,
+    pub flags: Option<&'a str>,
+    pub hops: Option<&'a str>,
+    pub idle: Option<&'a str>,
+    pub account: Option<&'a str>,
+    pub oplevel: Option<&'a str>,
+    pub realname: Option<&'a str>,
+}
+
+#[cfg(test)]
+mod tests {
+    use tests::{feed, joined};
+    use super::{WhoxQuery, WhoxReply};
+
+    fn args(line: &str) -> Vec<String> {
+        line.split(' ').map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_field_order() {
+        let query = WhoxQuery::new("rnaxc", 5).unwrap();
+        assert_eq!(query.fields(), "tcnar");
+        assert_eq!(query.command("#chan"), "WHO #chan %tcnar,5");
+        assert_eq!(WhoxQuery::new("n", 999).unwrap().fields(), "tn");
+        assert!(WhoxQuery::new("n", 1000).is_none());
+    }
+
+    #[test]
+    fn test_parse_reply() {
+        let query = WhoxQuery::new("cuihnar", 42).unwrap();
+        let reply = args("42 #chan user 10.0.0.1 host nick acct Real");
+        assert_eq!(query.parse_reply(&reply), Some(WhoxReply {
+            channel: Some("#chan"),
+            username: Some("user"),
+            ip: Some("10.0.0.1"),
+            hostname: Some("host"),
+            nick: Some("nick"),
+            account: Some("acct"),
+            realname: Some("Real"),
+            ..WhoxReply::default()
+        }));
+
+        let reply = args("42 * user 255.255.255.255 host nick 0 Real");
+        let parsed = query.parse_reply(&reply).unwrap();
+        assert_eq!(parsed.channel, None);
+        assert_eq!(parsed.ip, None);
+        assert_eq!(parsed.account, Some("0"));
+    }
+
+    #[test]
+    fn test_parse_reply_mismatch() {
+        let query = WhoxQuery::new("cn", 42).unwrap();
+        assert_eq!(query.parse_reply(&args("7 #chan nick")), None);
+        assert_eq!(query.parse_reply(&args("42 #chan")), None);
+        assert_eq!(query.parse_reply(&args("42 #chan nick extra")), None);
+    }
+
+    #[test]
+    fn test_reply_updates_members() {
+        let mut state = joined("#chan", &["me", "alice"]);
+        feed(&mut state, ":server 005 me WHOX :are supported").unwrap();
+        let chan_id = state.identify_channel("#chan").unwrap();
+        let query = WhoxQuery::new("cuhnfar", 42).unwrap();
+        assert_eq!(state.who_command("#chan", &query), "WHO #chan %tcuhnfar,42");
+
+        feed(&mut state, ":server 354 me 42 #chan b bob.host bob H@ bacct :Bob").unwrap();
+        feed(&mut state, ":server 354 me 42 #chan a alice.host alice G 0 :Alice").unwrap();
+        feed(&mut state, ":server 354 me 42 * c carol.host carol H 0 :Carol").unwrap();
+        feed(&mut state, ":server 354 me 7 #chan d dave.host dave H 0 :Dave").unwrap();
+
+        let bob = state.identify_nick("bob").unwrap();
+        assert!(state.is_op(chan_id, bob));
+        assert_eq!(state.resolve_user(bob).unwrap().account(), Some("bacct"));
+        assert_eq!(state.resolve_user(bob).unwrap().get_prefix(), "bob!b@bob.host");
+        let alice = state.identify_nick("alice").unwrap();
+        assert!(state.membership(chan_id, alice).is_some());
+        assert_eq!(state.resolve_user(alice).unwrap().account(), None);
+        assert_eq!(state.resolve_user(alice).unwrap().realname(), Some("Alice"));
+        assert_eq!(state.identify_nick("carol"), None);
+        assert_eq!(state.identify_nick("dave"), None);
+
+        // Replies after the end of the list don't belong to the query.
+        feed(&mut state, ":server 315 me #chan :End of WHO list").unwrap();
+        feed(&mut state, ":server 354 me 42 #chan e eve.host eve H 0 :Eve").unwrap();
+        assert_eq!(state.identify_nick("eve"), None);
+    }
+}